@@ -0,0 +1,288 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "Strict" => Some(SameSite::Strict),
+            "Lax" => Some(SameSite::Lax),
+            "None" => Some(SameSite::None),
+            _ => None,
+        }
+    }
+}
+
+/// A cookie set by a page. Pages set cookies with the generated prelude's
+/// `cookie()` builder, which works without this crate; the server reads
+/// them back into this type. `Display` renders the `Set-Cookie` header value.
+///
+/// New cookies default to `Path=/` and `HttpOnly`, like `SetCookie` always did:
+///
+/// ```
+/// use rsp_runtime::{Cookie, SameSite};
+///
+/// let cookie = Cookie::new("sid", "a b").same_site(SameSite::Lax).secure();
+/// assert_eq!(cookie.to_string(), "sid=a%20b; Path=/; SameSite=Lax; Secure; HttpOnly");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub max_age: Option<i64>,
+    pub expires: Option<SystemTime>,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub same_site: Option<SameSite>,
+    pub secure: bool,
+    pub http_only: bool,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            max_age: None,
+            expires: None,
+            path: Some("/".to_string()),
+            domain: None,
+            same_site: None,
+            secure: false,
+            http_only: true,
+        }
+    }
+
+    /// A cookie that tells the browser to delete `name` right away.
+    pub fn removal(name: &str) -> Self {
+        Cookie::new(name, "").max_age(0)
+    }
+
+    /// Cookie names must be an HTTP token: no spaces, `=`, `;` and the like.
+    pub fn has_valid_name(&self) -> bool {
+        !self.name.is_empty()
+            && self
+                .name
+                .bytes()
+                .all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b))
+    }
+
+    pub fn max_age(mut self, seconds: i64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    pub fn expires(mut self, at: SystemTime) -> Self {
+        self.expires = Some(at);
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+}
+
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, encode_value(&self.value))?;
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age)?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", http_date(expires))?;
+        }
+        // Attribute values end at `;`, so drop anything that would start a new one.
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", attribute_value(path))?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", attribute_value(domain))?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site.as_str())?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        Ok(())
+    }
+}
+
+/// Percent-encodes everything outside RFC 6265's `cookie-octet`, plus `%`
+/// and `+` so the value decodes back to itself.
+fn encode_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'%' | b'+' => encoded.push_str(&format!("%{:02X}", byte)),
+            0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn attribute_value(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| *c != ';' && !c.is_control())
+        .collect()
+}
+
+/// Formats `time` as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+    let days = secs / 86400;
+    let rem = secs % 86400;
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_encoding() {
+        assert_eq!(
+            Cookie::new("a", "plain-Value_1").to_string(),
+            "a=plain-Value_1; Path=/; HttpOnly"
+        );
+        assert_eq!(
+            Cookie::new("a", "x y;z,\"q\"\\").to_string(),
+            "a=x%20y%3Bz%2C%22q%22%5C; Path=/; HttpOnly"
+        );
+        assert_eq!(
+            Cookie::new("a", "50%+1").to_string(),
+            "a=50%25%2B1; Path=/; HttpOnly"
+        );
+        assert_eq!(
+            Cookie::new("a", "é\n").to_string(),
+            "a=%C3%A9%0A; Path=/; HttpOnly"
+        );
+    }
+
+    #[test]
+    fn test_attributes() {
+        let cookie = Cookie::new("sid", "1")
+            .max_age(3600)
+            .path("/app;evil")
+            .domain("example.com")
+            .http_only(false);
+        assert_eq!(
+            cookie.to_string(),
+            "sid=1; Max-Age=3600; Path=/appevil; Domain=example.com"
+        );
+        assert_eq!(
+            Cookie::removal("sid").to_string(),
+            "sid=; Max-Age=0; Path=/; HttpOnly"
+        );
+        assert!(Cookie::new("sid", "").has_valid_name());
+        assert!(!Cookie::new("s id", "").has_valid_name());
+        assert!(!Cookie::new("", "").has_valid_name());
+    }
+
+    #[test]
+    fn test_expires_formatting() {
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(
+            http_date(UNIX_EPOCH + Duration::from_secs(784111777)),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+        // A leap day.
+        assert_eq!(
+            http_date(UNIX_EPOCH + Duration::from_secs(951825600)),
+            "Tue, 29 Feb 2000 12:00:00 GMT"
+        );
+        let cookie = Cookie::new("a", "b").expires(UNIX_EPOCH + Duration::from_secs(784111777));
+        assert_eq!(
+            cookie.to_string(),
+            "a=b; Expires=Sun, 06 Nov 1994 08:49:37 GMT; Path=/; HttpOnly"
+        );
+    }
+
+    #[test]
+    fn test_same_site() {
+        for (same_site, name) in [
+            (SameSite::Strict, "Strict"),
+            (SameSite::Lax, "Lax"),
+            (SameSite::None, "None"),
+        ] {
+            assert_eq!(same_site.as_str(), name);
+            assert_eq!(SameSite::parse(name), Some(same_site));
+            assert_eq!(
+                Cookie::new("a", "b").same_site(same_site).to_string(),
+                format!("a=b; Path=/; SameSite={}; HttpOnly", name)
+            );
+        }
+        assert_eq!(SameSite::parse("lax"), None);
+    }
+}
@@ -0,0 +1,32 @@
+use std::fmt;
+
+/// Markup that `<%= %>` prints as-is instead of escaping it.
+///
+/// Only wrap HTML you built or sanitized yourself:
+/// `<%= SafeHtml(markdown_to_html(&post)) %>`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SafeHtml(pub String);
+
+impl SafeHtml {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SafeHtml {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<String> for SafeHtml {
+    fn from(html: String) -> Self {
+        SafeHtml(html)
+    }
+}
+
+impl From<&str> for SafeHtml {
+    fn from(html: &str) -> Self {
+        SafeHtml(html.to_string())
+    }
+}
@@ -1,19 +1,22 @@
+pub mod cookie;
 pub mod db;
+pub mod html;
 pub mod request;
-pub mod response;
 
+pub use cookie::{Cookie, SameSite};
 pub use db::Database;
-pub use request::{escape_html, Cookies, Headers, Params, Request};
-pub use response::ResponseControl;
+pub use html::SafeHtml;
+pub use request::{escape_html, Cookies, ErrorInfo, Headers, Params, Request, RequestData};
+
+/// Version of this crate; pages record the one they were built against.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 thread_local! {
-    static CURRENT_REQUEST: std::cell::RefCell<Option<Request>> = std::cell::RefCell::new(None);
-    static RESPONSE_CONTROL: std::cell::RefCell<ResponseControl> = std::cell::RefCell::new(ResponseControl::new());
+    static CURRENT_REQUEST: std::cell::RefCell<Option<Request>> = const { std::cell::RefCell::new(None) };
 }
 
 pub fn set_request(req: Request) {
     CURRENT_REQUEST.with(|r| *r.borrow_mut() = Some(req));
-    RESPONSE_CONTROL.with(|r| *r.borrow_mut() = ResponseControl::new());
 }
 
 pub fn req() -> Request {
@@ -23,34 +26,3 @@ pub fn req() -> Request {
 pub fn clear_request() {
     CURRENT_REQUEST.with(|r| *r.borrow_mut() = None);
 }
-
-pub fn get_response_control() -> ResponseControl {
-    RESPONSE_CONTROL.with(|r| r.borrow().clone())
-}
-
-pub fn header(status_code: u16) {
-    RESPONSE_CONTROL.with(|r| r.borrow_mut().status_code = status_code);
-}
-
-pub fn header_url(url: &str) {
-    RESPONSE_CONTROL.with(|r| {
-        r.borrow_mut().redirect = Some(url.to_string());
-        r.borrow_mut().status_code = 302;
-    });
-}
-
-pub fn SetCookie(name: &str, value: &str, max_age: i64) {
-    RESPONSE_CONTROL.with(|r| {
-        r.borrow_mut()
-            .cookies
-            .push((name.to_string(), value.to_string(), max_age));
-    });
-}
-
-pub fn CleanCookie(name: &str) {
-    RESPONSE_CONTROL.with(|r| {
-        r.borrow_mut()
-            .cookies
-            .push((name.to_string(), "".to_string(), -1));
-    });
-}
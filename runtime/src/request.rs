@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::ops::Index;
 
@@ -88,50 +89,74 @@ impl Index<&str> for Cookies {
     }
 }
 
+/// Why an error page is being rendered; templates see it as `req.error`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ErrorInfo {
+    /// HTTP status of the response, e.g. 404.
+    pub status: u16,
+    /// Path of the request that failed.
+    pub path: String,
+    /// What went wrong, in development mode only.
+    pub details: Option<String>,
+}
+
+/// The raw request as the server passes it to a page, serialized as JSON.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RequestData {
+    pub method: String,
+    pub path: String,
+    /// Query string without the leading `?`.
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    /// Parameters captured by a `routes.toml` pattern such as `/post/{id}`.
+    #[serde(default)]
+    pub route: HashMap<String, String>,
+    /// Set when the page renders as an error page.
+    #[serde(default)]
+    pub error: Option<ErrorInfo>,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Request {
     pub get: Params,
     pub post: Params,
     pub cookie: Cookies,
     pub ua: Headers,
+    /// Parameters from the route that matched, e.g. `req.route["id"]`.
+    pub route: Params,
+    /// Set on error pages such as `404.rsp`.
+    pub error: Option<ErrorInfo>,
     method: String,
     path: String,
     body: String,
 }
 
 impl Request {
+    /// The request of the render currently running on this thread; same as
+    /// [`crate::req`].
     pub fn new() -> Self {
-        let get: HashMap<String, String> = std::env::var("QUERY_STRING")
-            .unwrap_or_default()
-            .split('&')
-            .filter_map(|p| {
-                let mut parts = p.splitn(2, '=');
-                let key = parts.next()?.to_string();
-                if key.is_empty() {
-                    return None;
-                }
-                let value = urldecode(parts.next().unwrap_or(""));
-                Some((key, value))
-            })
-            .collect();
-
-        let body = std::env::var("RSP_BODY").unwrap_or_default();
+        crate::req()
+    }
 
-        let post: HashMap<String, String> = body
-            .split('&')
-            .filter_map(|p| {
-                let mut parts = p.splitn(2, '=');
-                let key = parts.next()?.to_string();
-                if key.is_empty() {
-                    return None;
-                }
-                let value = urldecode(parts.next().unwrap_or(""));
-                Some((key, value))
-            })
-            .collect();
+    pub fn from_data(data: RequestData) -> Self {
+        let mut headers: HashMap<String, String> = HashMap::new();
+        for (name, value) in data.headers {
+            let name = name.to_lowercase();
+            let separator = if name == "cookie" { "; " } else { ", " };
+            headers
+                .entry(name)
+                .and_modify(|v| {
+                    v.push_str(separator);
+                    v.push_str(&value);
+                })
+                .or_insert(value);
+        }
 
-        let cookie: HashMap<String, String> = std::env::var("HTTP_COOKIE")
-            .unwrap_or_default()
+        let cookie = headers
+            .get("cookie")
+            .map(String::as_str)
+            .unwrap_or("")
             .split(';')
             .filter_map(|c| {
                 let c = c.trim();
@@ -145,32 +170,33 @@ impl Request {
             })
             .collect();
 
-        let mut headers = HashMap::new();
-        for (key, value) in std::env::vars() {
-            if key.starts_with("HTTP_") {
-                let header_name = key[5..].replace('_', "-").to_lowercase();
-                headers.insert(header_name, value);
-            }
-        }
-
-        if let Ok(ct) = std::env::var("CONTENT_TYPE") {
-            headers.insert("content-type".to_string(), ct);
-        }
-        if let Ok(cl) = std::env::var("CONTENT_LENGTH") {
-            headers.insert("content-length".to_string(), cl);
-        }
-
         Request {
-            get: Params(get),
-            post: Params(post),
+            get: Params(parse_query(&data.query)),
+            post: Params(parse_query(&data.body)),
             cookie: Cookies(cookie),
             ua: Headers(headers),
-            method: std::env::var("REQUEST_METHOD").unwrap_or("GET".to_string()),
-            path: std::env::var("REQUEST_URI").unwrap_or("/".to_string()),
-            body,
+            route: Params(data.route),
+            error: data.error,
+            method: if data.method.is_empty() {
+                "GET".to_string()
+            } else {
+                data.method
+            },
+            path: if data.path.is_empty() {
+                "/".to_string()
+            } else {
+                data.path
+            },
+            body: data.body,
         }
     }
 
+    /// Decodes what the server handed to a page's `render`. Anything
+    /// unreadable becomes an empty GET request.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Request::from_data(serde_json::from_slice(bytes).unwrap_or_default())
+    }
+
     pub fn get_i64(&self, key: &str) -> Option<i64> {
         self.get.0.get(key).and_then(|v| v.parse().ok())
     }
@@ -209,22 +235,38 @@ impl Request {
     }
 }
 
+fn parse_query(s: &str) -> HashMap<String, String> {
+    s.split('&')
+        .filter_map(|p| {
+            let mut parts = p.splitn(2, '=');
+            let key = parts.next()?.to_string();
+            if key.is_empty() {
+                return None;
+            }
+            let value = urldecode(parts.next().unwrap_or(""));
+            Some((key, value))
+        })
+        .collect()
+}
+
 fn urldecode(s: &str) -> String {
-    let mut result = String::new();
-    let mut chars = s.chars();
-    while let Some(c) = chars.next() {
-        if c == '+' {
-            result.push(' ');
-        } else if c == '%' {
-            let hex: String = chars.by_ref().take(2).collect();
-            if let Ok(byte) = u8::from_str_radix(&hex, 16) {
-                result.push(byte as char);
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = std::str::from_utf8(&rest[..rest.len().min(2)]).unwrap_or("");
+                if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                    bytes.push(byte);
+                }
+                rest = &rest[rest.len().min(2)..];
             }
-        } else {
-            result.push(c);
+            _ => bytes.push(b),
         }
     }
-    result
+    String::from_utf8_lossy(&bytes).into_owned()
 }
 
 pub fn escape_html(s: &str) -> String {
@@ -234,3 +276,82 @@ pub fn escape_html(s: &str) -> String {
         .replace('"', "&quot;")
         .replace('\'', "&#39;")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clear_request, req, set_request};
+
+    fn data(query: &str, body: &str, headers: &[(&str, &str)]) -> RequestData {
+        RequestData {
+            method: "POST".to_string(),
+            path: "/form.rsp".to_string(),
+            query: query.to_string(),
+            headers: headers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            body: body.to_string(),
+            route: HashMap::from([("id".to_string(), "7".to_string())]),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_from_bytes_round_trip() {
+        let data = data(
+            "q=caf%C3%A9+au+lait&n=3&flag",
+            "title=a%26b&n=x",
+            &[
+                ("User-Agent", "test"),
+                ("Cookie", "sid=abc"),
+                ("cookie", "theme=dark%20blue"),
+                ("Accept", "text/html"),
+                ("accept", "*/*"),
+            ],
+        );
+        let req = Request::from_bytes(&serde_json::to_vec(&data).unwrap());
+
+        assert_eq!(req.method(), "POST");
+        assert!(req.is_post());
+        assert_eq!(req.path(), "/form.rsp");
+        assert_eq!(&req.get["q"], "café au lait");
+        assert_eq!(req.get_i64("n"), Some(3));
+        assert_eq!(req.get.get("flag").map(String::as_str), Some(""));
+        assert_eq!(&req.post["title"], "a&b");
+        assert_eq!(req.post_i64("n"), None);
+        assert_eq!(req.body(), "title=a%26b&n=x");
+        assert_eq!(&req.cookie["sid"], "abc");
+        assert_eq!(&req.cookie["theme"], "dark blue");
+        assert_eq!(&req.ua["user-agent"], "test");
+        assert_eq!(&req.ua["ACCEPT"], "text/html, */*");
+        assert_eq!(&req.route["id"], "7");
+        assert!(req.error.is_none());
+    }
+
+    #[test]
+    fn test_from_bytes_defaults() {
+        for bytes in [&b""[..], b"not json", b"{}"] {
+            let req = Request::from_bytes(bytes);
+            assert_eq!(req.method(), "GET");
+            assert_eq!(req.path(), "/");
+            assert!(req.get.get("q").is_none());
+            assert_eq!(&req.cookie["sid"], "");
+        }
+    }
+
+    #[test]
+    fn test_set_request_replaces_previous() {
+        set_request(Request::from_data(data("a=1", "", &[("Cookie", "sid=1")])));
+        assert_eq!(&req().get["a"], "1");
+
+        set_request(Request::from_data(data("b=2", "", &[])));
+        let second = req();
+        assert_eq!(&second.get["b"], "2");
+        assert!(second.get.get("a").is_none());
+        assert!(second.cookie.get("sid").is_none());
+
+        clear_request();
+        assert!(req().get.get("b").is_none());
+    }
+}
@@ -0,0 +1,308 @@
+use crate::router::percent_decode;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Files the server never hands out by default: dotfiles and dot
+/// directories (`.rspcache`, `.git`, `.env`), databases, config files and
+/// compiled libraries.
+pub const DEFAULT_DENY: &[&str] = &[
+    ".*",
+    "*.db",
+    "*.sqlite",
+    "*.sqlite3",
+    "*.toml",
+    "*.lock",
+    "*.so",
+    "*.dylib",
+    "*.dll",
+];
+
+/// Decides which request paths may reach files in the docroot.
+///
+/// Deny and allow patterns use `*` and `?` wildcards. A pattern without a
+/// `/` is matched against every segment of the path, so `.*` covers
+/// `/.git/config` as well as `/.env`; one with a `/` is matched against the
+/// whole path relative to the docroot, e.g. `private/*`. Allow patterns win
+/// over deny patterns. Matching ignores case, since `/FORUM.DB` reaches
+/// `forum.db` on case-insensitive filesystems.
+#[derive(Debug, Clone)]
+pub struct AccessRules {
+    pub deny: Vec<String>,
+    pub allow: Vec<String>,
+    docroot: PathBuf,
+    /// The compiled-page cache, relative to the docroot, if it lives there.
+    cache_dir: Option<PathBuf>,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum AccessError {
+    #[error("path leads outside the document root")]
+    Traversal,
+    #[error("path matches deny rule {0:?}")]
+    Denied(String),
+}
+
+impl AccessRules {
+    pub fn new(docroot: &Path, cache_dir: &Path) -> Self {
+        let docroot = docroot
+            .canonicalize()
+            .unwrap_or_else(|_| docroot.to_path_buf());
+        let cache_dir = cache_dir
+            .canonicalize()
+            .ok()
+            .and_then(|dir| dir.strip_prefix(&docroot).ok().map(Path::to_path_buf))
+            .filter(|dir| !dir.as_os_str().is_empty());
+        AccessRules {
+            deny: DEFAULT_DENY.iter().map(|p| p.to_string()).collect(),
+            allow: Vec::new(),
+            docroot,
+            cache_dir,
+        }
+    }
+
+    /// Checks the path of a request URL. Returns it percent-decoded and
+    /// normalized, still starting with `/` and keeping a trailing `/`.
+    pub fn check(&self, uri_path: &str) -> Result<String, AccessError> {
+        let decoded = percent_decode(uri_path);
+        if decoded.contains('\0') {
+            return Err(AccessError::Traversal);
+        }
+        let mut segments = Vec::new();
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return Err(AccessError::Traversal),
+                s => segments.push(s),
+            }
+        }
+        let relative = segments.join("/");
+
+        // A symlink may still point out of the docroot; resolve the part of
+        // the path that exists.
+        let full = self.docroot.join(&relative);
+        if let Some(existing) = full.ancestors().find_map(|p| p.canonicalize().ok()) {
+            if !existing.starts_with(&self.docroot) {
+                return Err(AccessError::Traversal);
+            }
+        }
+
+        if let Some(cache_dir) = &self.cache_dir {
+            let cache = cache_dir.to_string_lossy().to_lowercase();
+            if Path::new(&relative.to_lowercase()).starts_with(cache) {
+                return Err(AccessError::Denied(cache_dir.display().to_string()));
+            }
+        }
+        if let Some(rule) = self.denied_by(&relative, &segments) {
+            return Err(AccessError::Denied(rule.clone()));
+        }
+
+        let trailing = if decoded.ends_with('/') && !relative.is_empty() {
+            "/"
+        } else {
+            ""
+        };
+        Ok(format!("/{}{}", relative, trailing))
+    }
+
+    /// Checks a path, as returned by `check`, that is about to be served as
+    /// a static file. Template sources are never served as they are.
+    pub fn check_static(&self, path: &str) -> Result<(), AccessError> {
+        let name = path.rsplit('/').next().unwrap_or("");
+        if name.to_ascii_lowercase().ends_with(".rsp") {
+            return Err(AccessError::Denied("*.rsp".to_string()));
+        }
+        Ok(())
+    }
+
+    fn denied_by(&self, relative: &str, segments: &[&str]) -> Option<&String> {
+        let matches = |pattern: &String| {
+            if pattern.contains('/') {
+                glob_match(pattern.trim_start_matches('/'), relative)
+            } else {
+                segments.iter().any(|s| glob_match(pattern, s))
+            }
+        };
+        if self.allow.iter().any(matches) {
+            return None;
+        }
+        self.deny.iter().find(|p| matches(p))
+    }
+}
+
+/// Matches `text` against a pattern where `*` stands for any run of
+/// characters and `?` for any one character, ignoring case.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and how much of `text` it has taken so far.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn docroot(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rsp-access-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("www/forum")).unwrap();
+        std::fs::write(dir.join("www/forum/index.rsp"), "").unwrap();
+        std::fs::write(dir.join("secret.txt"), "").unwrap();
+        dir
+    }
+
+    fn denied<T>(rule: &str) -> Result<T, AccessError> {
+        Err(AccessError::Denied(rule.to_string()))
+    }
+
+    #[test]
+    fn test_allows_normal_paths() {
+        let dir = docroot("normal");
+        let rules = AccessRules::new(&dir.join("www"), &dir.join("www/.rspcache"));
+        assert_eq!(rules.check("/"), Ok("/".to_string()));
+        assert_eq!(rules.check("/forum/"), Ok("/forum/".to_string()));
+        assert_eq!(
+            rules.check("//forum/./index.rsp"),
+            Ok("/forum/index.rsp".to_string())
+        );
+        assert_eq!(rules.check("/my%20page"), Ok("/my page".to_string()));
+        assert_eq!(rules.check("/file.tomlx"), Ok("/file.tomlx".to_string()));
+    }
+
+    #[test]
+    fn test_rejects_traversal() {
+        let dir = docroot("traversal");
+        let rules = AccessRules::new(&dir.join("www"), &dir.join("www/.rspcache"));
+        assert_eq!(rules.check("/../secret.txt"), Err(AccessError::Traversal));
+        assert_eq!(
+            rules.check("/forum/../../secret.txt"),
+            Err(AccessError::Traversal)
+        );
+        assert_eq!(
+            rules.check("/%2e%2e/secret.txt"),
+            Err(AccessError::Traversal)
+        );
+        assert_eq!(
+            rules.check("/forum%2f..%2f..%2fsecret.txt"),
+            Err(AccessError::Traversal)
+        );
+        assert_eq!(rules.check("/a%00.rsp"), Err(AccessError::Traversal));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rejects_symlinks_out_of_docroot() {
+        let dir = docroot("symlink");
+        std::os::unix::fs::symlink(&dir, dir.join("www/up")).unwrap();
+        std::os::unix::fs::symlink(dir.join("www/forum"), dir.join("www/board")).unwrap();
+        let rules = AccessRules::new(&dir.join("www"), &dir.join("www/.rspcache"));
+        assert_eq!(rules.check("/up/secret.txt"), Err(AccessError::Traversal));
+        assert_eq!(rules.check("/up/missing.txt"), Err(AccessError::Traversal));
+        assert_eq!(
+            rules.check("/board/index.rsp"),
+            Ok("/board/index.rsp".to_string())
+        );
+    }
+
+    #[test]
+    fn test_default_deny_list() {
+        let dir = docroot("deny");
+        let rules = AccessRules::new(&dir.join("www"), &dir.join("www/.rspcache"));
+        assert_eq!(rules.check("/.rspcache/abc.so"), denied(".*"));
+        assert_eq!(rules.check("/.git/config"), denied(".*"));
+        assert_eq!(rules.check("/forum/.env"), denied(".*"));
+        assert_eq!(rules.check("/forum.db"), denied("*.db"));
+        assert_eq!(rules.check("/data/app.sqlite3"), denied("*.sqlite3"));
+        assert_eq!(rules.check("/rsp.toml"), denied("*.toml"));
+        assert_eq!(rules.check("/routes.toml"), denied("*.toml"));
+        assert_eq!(rules.check("/lib.so"), denied("*.so"));
+    }
+
+    #[test]
+    fn test_deny_rules_ignore_case() {
+        let dir = docroot("case");
+        std::fs::create_dir_all(dir.join("www/cache")).unwrap();
+        let mut rules = AccessRules::new(&dir.join("www"), &dir.join("www/cache"));
+        rules.deny.push("Private/*".to_string());
+        rules.allow.push("PUBLIC.toml".to_string());
+        assert_eq!(rules.check("/forum.DB"), denied("*.db"));
+        assert_eq!(rules.check("/ROUTES.TOML"), denied("*.toml"));
+        assert_eq!(rules.check("/data/App.Sqlite3"), denied("*.sqlite3"));
+        assert_eq!(rules.check("/lib.SO"), denied("*.so"));
+        assert_eq!(rules.check("/CACHE/abc"), denied("cache"));
+        assert_eq!(rules.check("/private/a.txt"), denied("Private/*"));
+        assert_eq!(rules.check("/public.TOML"), Ok("/public.TOML".to_string()));
+    }
+
+    #[test]
+    fn test_denies_cache_dir_inside_docroot() {
+        let dir = docroot("cache");
+        std::fs::create_dir_all(dir.join("www/cache")).unwrap();
+        let rules = AccessRules::new(&dir.join("www"), &dir.join("www/cache"));
+        assert_eq!(rules.check("/cache/abc.so"), denied("cache"));
+        assert_eq!(rules.check("/cached.html"), Ok("/cached.html".to_string()));
+    }
+
+    #[test]
+    fn test_configured_rules() {
+        let dir = docroot("configured");
+        let mut rules = AccessRules::new(&dir.join("www"), &dir.join("www/.rspcache"));
+        rules.deny.push("private/*".to_string());
+        rules.deny.push("*.bak".to_string());
+        rules.allow.push(".well-known".to_string());
+        assert_eq!(rules.check("/private/a.txt"), denied("private/*"));
+        assert_eq!(
+            rules.check("/forum/private/a.txt"),
+            Ok("/forum/private/a.txt".to_string())
+        );
+        assert_eq!(rules.check("/index.rsp.bak"), denied("*.bak"));
+        assert_eq!(
+            rules.check("/.well-known/acme-challenge/x"),
+            Ok("/.well-known/acme-challenge/x".to_string())
+        );
+    }
+
+    #[test]
+    fn test_never_serves_template_source() {
+        let dir = docroot("source");
+        let rules = AccessRules::new(&dir.join("www"), &dir.join("www/.rspcache"));
+        assert_eq!(rules.check_static("/forum/index.rsp"), denied("*.rsp"));
+        assert_eq!(rules.check_static("/forum/INDEX.RSP"), denied("*.rsp"));
+        assert_eq!(rules.check_static("/style.css"), Ok(()));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.db", "forum.db"));
+        assert!(glob_match("*.db", ".db"));
+        assert!(!glob_match("*.db", "forum.dbx"));
+        assert!(glob_match("a?c*", "abcdef"));
+        assert!(glob_match("*a*b", "xxaxxb"));
+        assert!(!glob_match("*a*b", "xxaxxbc"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*.db", "FORUM.DB"));
+        assert!(glob_match("Private/*", "private/a"));
+    }
+}
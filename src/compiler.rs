@@ -1,3 +1,6 @@
+use crate::source_map::{numbered_lines, SourceMap, TemplateLocation};
+use serde::Deserialize;
+use std::fmt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use thiserror::Error;
@@ -8,6 +11,238 @@ pub enum CompileError {
     Io(#[from] std::io::Error),
     #[error("Compilation failed:\n{0}")]
     Compile(String),
+    #[error("Compilation failed:\n{}", format_diagnostics(.0))]
+    Diagnostics(Vec<Diagnostic>),
+}
+
+// `std::io::Error` isn't `Clone`; copies keep its kind and message, which
+// is all anyone waiting on a shared build gets to see anyway.
+impl Clone for CompileError {
+    fn clone(&self) -> Self {
+        match self {
+            CompileError::Io(e) => CompileError::Io(std::io::Error::new(e.kind(), e.to_string())),
+            CompileError::Compile(stderr) => CompileError::Compile(stderr.clone()),
+            CompileError::Diagnostics(diagnostics) => {
+                CompileError::Diagnostics(diagnostics.clone())
+            }
+        }
+    }
+}
+
+impl CompileError {
+    /// Points every diagnostic that falls inside the generated page at the
+    /// template line it was generated from.
+    pub fn with_source_map(self, source_map: &SourceMap) -> Self {
+        match self {
+            CompileError::Diagnostics(mut diagnostics) => {
+                for diagnostic in &mut diagnostics {
+                    if let Some(span) = diagnostic.generated {
+                        diagnostic.location = source_map.lookup(span.line, span.column);
+                        if let Some(location) = &diagnostic.location {
+                            diagnostic.snippet = source_map.line_text(location).map(str::to_string);
+                            diagnostic.excerpt =
+                                source_map.lines_around(location.source, location.line, 3);
+                        }
+                    }
+                }
+                CompileError::Diagnostics(diagnostics)
+            }
+            other => other,
+        }
+    }
+}
+
+/// One error reported by rustc.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub level: String,
+    pub code: Option<String>,
+    pub message: String,
+    /// Label rustc attached to the primary span, e.g. "not found in this scope".
+    pub label: Option<String>,
+    /// Primary span in the generated source, if it points into the page.
+    pub generated: Option<GeneratedSpan>,
+    /// Where `generated` came from in the template, once mapped.
+    pub location: Option<TemplateLocation>,
+    /// The template line at `location`.
+    pub snippet: Option<String>,
+    /// Numbered template lines around `location`.
+    pub excerpt: Vec<(usize, String)>,
+    /// Numbered lines of the generated source around `generated`.
+    pub generated_excerpt: Vec<(usize, String)>,
+    /// `help:`/`note:` lines attached to the error.
+    pub notes: Vec<String>,
+    /// rustc's own rendering, used when the error can't be mapped.
+    pub rendered: String,
+}
+
+/// 1-based line and column range on a single line of the generated source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GeneratedSpan {
+    pub line: usize,
+    pub column: usize,
+    pub column_end: usize,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(location) = &self.location else {
+            return write!(f, "{}", self.rendered.trim_end());
+        };
+
+        match &self.code {
+            Some(code) => writeln!(f, "{}[{}]: {}", self.level, code, self.message)?,
+            None => writeln!(f, "{}: {}", self.level, self.message)?,
+        }
+
+        let gutter = " ".repeat(location.line.to_string().len());
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter, location.file, location.line, location.column
+        )?;
+        if let Some(snippet) = &self.snippet {
+            let width = self
+                .generated
+                .map(|s| s.column_end.saturating_sub(s.column))
+                .unwrap_or(1)
+                .max(1);
+            writeln!(f, "{} |", gutter)?;
+            writeln!(f, "{} | {}", location.line, snippet)?;
+            write!(
+                f,
+                "{} | {}{}",
+                gutter,
+                " ".repeat(location.column - 1),
+                "^".repeat(width)
+            )?;
+            match &self.label {
+                Some(label) => writeln!(f, " {}", label)?,
+                None => writeln!(f)?,
+            }
+        }
+        for note in &self.notes {
+            writeln!(f, "{} = {}", gutter, note)?;
+        }
+        Ok(())
+    }
+}
+
+fn format_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[derive(Deserialize)]
+struct RustcDiagnostic {
+    message: String,
+    code: Option<RustcCode>,
+    level: String,
+    #[serde(default)]
+    spans: Vec<RustcSpan>,
+    #[serde(default)]
+    children: Vec<RustcDiagnostic>,
+    rendered: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RustcCode {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct RustcSpan {
+    file_name: String,
+    line_start: usize,
+    line_end: usize,
+    column_start: usize,
+    column_end: usize,
+    is_primary: bool,
+    label: Option<String>,
+    suggested_replacement: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    message: Option<RustcDiagnostic>,
+}
+
+/// Collects the errors from rustc's JSON output. `is_page` tells whether a
+/// span's file is the generated page source.
+fn parse_diagnostics<'a>(
+    lines: impl Iterator<Item = &'a str>,
+    source: &str,
+    is_page: impl Fn(&str) -> bool,
+) -> Vec<Diagnostic> {
+    lines
+        .filter_map(|line| {
+            let line = line.trim();
+            if !line.starts_with('{') {
+                return None;
+            }
+            match serde_json::from_str::<CargoMessage>(line) {
+                Ok(msg) if msg.reason == "compiler-message" => msg.message,
+                Ok(_) => None,
+                Err(_) => serde_json::from_str::<RustcDiagnostic>(line).ok(),
+            }
+        })
+        .filter(|d| d.level == "error" && !d.message.starts_with("aborting due to"))
+        .map(|d| {
+            let primary = d
+                .spans
+                .iter()
+                .find(|s| s.is_primary && is_page(&s.file_name));
+            Diagnostic {
+                level: d.level,
+                code: d.code.map(|c| c.code),
+                message: d.message,
+                label: primary.and_then(|s| s.label.clone()),
+                generated: primary.map(|s| GeneratedSpan {
+                    line: s.line_start,
+                    column: s.column_start,
+                    column_end: if s.line_end == s.line_start {
+                        s.column_end
+                    } else {
+                        s.column_start + 1
+                    },
+                }),
+                location: None,
+                snippet: None,
+                excerpt: Vec::new(),
+                generated_excerpt: primary
+                    .map(|s| numbered_lines(source, s.line_start, 3))
+                    .unwrap_or_default(),
+                notes: d
+                    .children
+                    .iter()
+                    .map(|c| {
+                        let suggestion = c
+                            .spans
+                            .iter()
+                            .find_map(|s| s.suggested_replacement.as_ref());
+                        match suggestion {
+                            Some(replacement) => {
+                                format!("{}: {}: `{}`", c.level, c.message, replacement)
+                            }
+                            None => format!("{}: {}", c.level, c.message),
+                        }
+                    })
+                    .collect(),
+                rendered: d.rendered.unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+/// Temporary name a library is written under before being moved into place.
+fn partial_path(output_path: &Path) -> PathBuf {
+    let mut name = output_path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.partial", std::process::id()));
+    output_path.with_file_name(name)
 }
 
 #[derive(Debug, Clone, Default)]
@@ -48,24 +283,32 @@ impl Compiler {
         let source_path = self.cache_dir.join(format!("{}.rs", hash));
         std::fs::write(&source_path, source)?;
 
+        let partial_path = partial_path(&output_path);
         let mut cmd = Command::new("rustc");
         cmd.arg(&source_path)
             .arg("--crate-type=cdylib")
             .arg("-o")
-            .arg(&output_path)
+            .arg(&partial_path)
             .arg("-C")
             .arg("opt-level=2")
             .arg("-C")
-            .arg("debuginfo=0");
+            .arg("debuginfo=0")
+            .arg("--error-format=json");
 
         let output = cmd.output()?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(CompileError::Compile(stderr.to_string()));
+            let page = source_path.to_string_lossy();
+            let diagnostics = parse_diagnostics(stderr.lines(), source, |file| file == page);
+            if diagnostics.is_empty() {
+                return Err(CompileError::Compile(stderr.to_string()));
+            }
+            return Err(CompileError::Diagnostics(diagnostics));
         }
 
         let _ = std::fs::remove_file(&source_path);
+        std::fs::rename(&partial_path, &output_path)?;
 
         Ok(output_path)
     }
@@ -95,14 +338,21 @@ impl Compiler {
         let mut cmd = Command::new("cargo");
         cmd.arg("build")
             .arg("--release")
+            .arg("--message-format=json")
             .current_dir(&project_dir)
             .env("CARGO_TARGET_DIR", &self.global_target);
 
         let output = cmd.output()?;
 
         if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(CompileError::Compile(stderr.to_string()));
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let diagnostics =
+                parse_diagnostics(stdout.lines(), source, |file| file == "src/lib.rs");
+            if diagnostics.is_empty() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(CompileError::Compile(stderr.to_string()));
+            }
+            return Err(CompileError::Diagnostics(diagnostics));
         }
 
         let pkg_name = format!("rsp_{}", hash);
@@ -122,7 +372,9 @@ impl Compiler {
         });
 
         if built_lib.exists() {
-            std::fs::copy(&built_lib, &output_path)?;
+            let partial_path = partial_path(&output_path);
+            std::fs::copy(&built_lib, &partial_path)?;
+            std::fs::rename(&partial_path, &output_path)?;
         }
 
         Ok(output_path)
@@ -169,7 +421,9 @@ codegen-units = 16
         )
     }
 
-    fn get_lib_path(&self, name: &str) -> PathBuf {
+    /// Where the compiled library for `name` ends up. It only appears once
+    /// complete, so an existing file is always safe to load.
+    pub(crate) fn get_lib_path(&self, name: &str) -> PathBuf {
         #[cfg(target_os = "linux")]
         let lib_name = format!("lib{}.so", name);
 
@@ -186,3 +440,52 @@ codegen-units = 16
         &self.cache_dir
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::Generator;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_diagnostics_map_to_template() {
+        let parsed = Parser::new()
+            .parse("<h1>Hi</h1>\n<p><%= nme %></p>\n")
+            .unwrap();
+        let generated = Generator::new().generate_full_source(&parsed).unwrap();
+        let line = generated
+            .source
+            .lines()
+            .position(|l| l.contains("nme"))
+            .unwrap()
+            + 1;
+        let column = generated
+            .source
+            .lines()
+            .nth(line - 1)
+            .unwrap()
+            .find("nme")
+            .unwrap()
+            + 1;
+
+        let json = format!(
+            r#"{{"message":"cannot find value `nme` in this scope","code":{{"code":"E0425"}},"level":"error","spans":[{{"file_name":"page.rs","line_start":{line},"line_end":{line},"column_start":{column},"column_end":{end},"is_primary":true,"label":"not found in this scope","suggested_replacement":null}}],"children":[],"rendered":"raw"}}"#,
+            line = line,
+            column = column,
+            end = column + 3,
+        );
+        let diagnostics =
+            parse_diagnostics(json.lines(), &generated.source, |file| file == "page.rs");
+        let error = CompileError::Diagnostics(diagnostics).with_source_map(&generated.source_map);
+
+        let CompileError::Diagnostics(diagnostics) = &error else {
+            panic!("expected diagnostics");
+        };
+        let location = diagnostics[0].location.as_ref().unwrap();
+        assert_eq!((location.line, location.column), (2, 8));
+        assert_eq!(diagnostics[0].snippet.as_deref(), Some("<p><%= nme %></p>"));
+        assert_eq!(diagnostics[0].excerpt.len(), 2);
+        assert!(diagnostics[0].generated_excerpt[3].1.contains("nme"));
+        assert!(error.to_string().contains("--> <template>:2:8"));
+    }
+}
@@ -1,25 +1,50 @@
 use crate::compiler::{CompileError, CompileOptions, Compiler};
-use crate::generator::Generator;
-use crate::loader::{LoadError, Loader};
+use crate::generator::{display_name, GenerateError, GenerateOptions, GeneratedCode, Generator};
+use crate::loader::{Chunk, LoadError, LoadedLib, Loader, PagePanic, PageResponse, ABI_VERSION};
 use crate::parser::{ParseError, Parser};
+use crate::source_map::{FileStamp, SourceMap, TemplateLocation};
+use rsp_runtime::{Cookie, RequestData};
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::SystemTime;
 
 #[derive(Debug, Clone)]
 pub struct RenderResult {
-    pub content: String,
+    /// The response body. Pages may write any bytes, not just UTF-8.
+    pub content: Vec<u8>,
     pub status_code: u16,
     pub redirect: Option<String>,
-    pub cookies: Vec<(String, String, i64)>,
+    pub cookies: Vec<Cookie>,
     pub headers: Vec<(String, String)>,
 }
 
+/// A piece of a streamed page, see `RspEngine::render_file_streaming`.
+pub enum StreamChunk<'a> {
+    /// Everything but the body, as it was when the page first flushed.
+    Head(RenderResult),
+    Body(&'a [u8]),
+}
+
+impl From<PageResponse> for RenderResult {
+    fn from((content, status_code, redirect, cookies, headers): PageResponse) -> Self {
+        RenderResult {
+            content,
+            status_code,
+            redirect,
+            cookies,
+            headers,
+        }
+    }
+}
+
 impl Default for RenderResult {
     fn default() -> Self {
         Self {
-            content: String::new(),
+            content: Vec::new(),
             status_code: 200,
             redirect: None,
             cookies: Vec::new(),
@@ -32,25 +57,92 @@ pub struct RspEngine {
     parser: Parser,
     generator: Generator,
     compiler: Compiler,
-    loader: std::sync::Mutex<Loader>,
+    loader: Loader,
+    builds: Builds,
     cache_dir: PathBuf,
     docroot: std::sync::Mutex<PathBuf>,
+    /// Compiled pages by canonical path, so a request for an unchanged page
+    /// goes straight to its library.
+    pages: RwLock<HashMap<PathBuf, Arc<PageEntry>>>,
+    /// Whether pages in `pages` are checked against their files before use.
+    check_changes: AtomicBool,
+}
+
+/// A page in the page table: its library, ready to run, and what its files
+/// looked like when it was built.
+struct PageEntry {
+    /// The page itself, then every file it includes or extends.
+    files: Vec<(PathBuf, FileStamp)>,
+    hash: String,
+    library: Arc<LoadedLib>,
+    source_map: SourceMap,
+}
+
+impl PageEntry {
+    /// Whether none of the page's files changed since it was built.
+    fn is_fresh(&self) -> bool {
+        self.files
+            .iter()
+            .all(|(path, stamp)| FileStamp::of(path).is_ok_and(|now| now == *stamp))
+    }
 }
 
 #[derive(Debug)]
 pub enum RspError {
     Parse(ParseError),
+    Generate(GenerateError),
     Compile(CompileError),
     Load(LoadError),
+    Panic(Box<PanicReport>),
     Io(std::io::Error),
 }
 
+/// A page panicked while rendering.
+#[derive(Debug, Clone)]
+pub struct PanicReport {
+    pub message: String,
+    /// `file:line:column` of the panic, as Rust reported it.
+    pub origin: String,
+    /// Template position of the panic, when it came from the page's own code.
+    pub location: Option<TemplateLocation>,
+    /// Numbered template lines around `location`.
+    pub excerpt: Vec<(usize, String)>,
+}
+
+impl PanicReport {
+    fn new(panic: PagePanic, source_map: &SourceMap) -> Self {
+        let location = panic
+            .generated
+            .and_then(|(line, column)| source_map.lookup(line, column));
+        let excerpt = location
+            .as_ref()
+            .map(|l| source_map.lines_around(l.source, l.line, 3))
+            .unwrap_or_default();
+        PanicReport {
+            message: panic.message,
+            origin: panic.origin,
+            location,
+            excerpt,
+        }
+    }
+}
+
 impl std::fmt::Display for RspError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            RspError::Parse(e) => write!(f, "Parse error: {}", e),
+            RspError::Parse(e) => write!(f, "{}", e),
+            RspError::Generate(e @ GenerateError::Parse { .. }) => write!(f, "{}", e),
+            RspError::Generate(e) => write!(f, "Template error: {}", e),
             RspError::Compile(e) => write!(f, "{}", e),
             RspError::Load(e) => write!(f, "Load error: {}", e),
+            RspError::Panic(report) => match &report.location {
+                Some(l) => write!(
+                    f,
+                    "Page panicked at {}:{}:{}: {}",
+                    l.file, l.line, l.column, report.message
+                ),
+                None => write!(f, "Page panicked at {}: {}", report.origin, report.message),
+            },
             RspError::Io(e) => write!(f, "IO error: {}", e),
         }
     }
@@ -58,12 +150,32 @@ impl std::fmt::Display for RspError {
 
 impl std::error::Error for RspError {}
 
+impl RspError {
+    /// Short human-readable name of the error kind.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RspError::Parse(_) | RspError::Generate(GenerateError::Parse { .. }) => "Parse error",
+            RspError::Generate(_) => "Template error",
+            RspError::Compile(_) => "Compile error",
+            RspError::Load(_) => "Load error",
+            RspError::Panic(_) => "Runtime error",
+            RspError::Io(_) => "IO error",
+        }
+    }
+}
+
 impl From<ParseError> for RspError {
     fn from(e: ParseError) -> Self {
         RspError::Parse(e)
     }
 }
 
+impl From<GenerateError> for RspError {
+    fn from(e: GenerateError) -> Self {
+        RspError::Generate(e)
+    }
+}
+
 impl From<CompileError> for RspError {
     fn from(e: CompileError) -> Self {
         RspError::Compile(e)
@@ -90,9 +202,12 @@ impl RspEngine {
             parser: Parser::new(),
             generator: Generator::new(),
             compiler: Compiler::new(cache_dir.clone()),
-            loader: std::sync::Mutex::new(Loader::new()),
+            loader: Loader::new(),
+            builds: Builds::new(DEFAULT_MAX_BUILDS),
             cache_dir,
             docroot: std::sync::Mutex::new(PathBuf::from(".")),
+            pages: RwLock::new(HashMap::new()),
+            check_changes: AtomicBool::new(true),
         })
     }
 
@@ -102,45 +217,258 @@ impl RspEngine {
         }
     }
 
+    /// Caps how many pages compile at the same time (at least one).
+    pub fn set_max_builds(&self, max: usize) {
+        self.builds.limit.store(max.max(1), Ordering::SeqCst);
+    }
+
+    /// Whether compiled pages are checked for changes before each request,
+    /// at the cost of a `stat` per file (the default). Turn it off when the
+    /// files can't change while the server runs; pages then only change
+    /// through `compile_file`.
+    pub fn set_check_changes(&self, check: bool) {
+        self.check_changes.store(check, Ordering::SeqCst);
+    }
+
     pub fn render(&self, rsp_content: &str) -> Result<RenderResult, RspError> {
-        let parsed = self.parser.parse(rsp_content)?;
-        let generated = self.generator.generate_full_source(&parsed);
+        let (generated, hash, lib_path) = self.build(rsp_content, None)?;
+        let library = self.load(&generated, &hash, &lib_path)?;
+        let response = library
+            .render(&RequestData::default())
+            .map_err(|e| render_error(e, &generated.source_map))?;
+        Ok(response.into())
+    }
 
+    /// Renders a page for one HTTP request, handing its output to `sink` as
+    /// the page calls `flush()` instead of collecting it. `sink` gets the
+    /// head exactly once, before any body chunk, unless this returns an
+    /// error before the page got that far.
+    pub fn render_file_streaming(
+        &self,
+        path: &Path,
+        request: &RequestData,
+        sink: &mut dyn FnMut(StreamChunk<'_>),
+    ) -> Result<(), RspError> {
+        let page = self.page(path)?;
+        let mut forward = |chunk: Chunk<'_>| match chunk {
+            Chunk::Head(head) => sink(StreamChunk::Head(head.into())),
+            Chunk::Body(bytes) => sink(StreamChunk::Body(bytes)),
+        };
+        page.library
+            .render_streaming(request, &mut forward)
+            .map_err(|e| render_error(e, &page.source_map))
+    }
+
+    /// Compiles a page without running it, e.g. to have it ready before the
+    /// first request, and puts it in the page table even if changes aren't
+    /// checked. Returns the files it includes or extends.
+    pub fn compile_file(&self, path: &Path) -> Result<Vec<PathBuf>, RspError> {
+        let page = match self.cached_page(path) {
+            Some(page) if page.is_fresh() => page,
+            _ => self.load_page(path)?,
+        };
+        Ok(page.files[1..]
+            .iter()
+            .map(|(file, _)| file.clone())
+            .collect())
+    }
+
+    /// Whether `path` has been compiled as a page of its own and is in the
+    /// page table.
+    pub fn has_page(&self, path: &Path) -> bool {
+        self.cached_page(path).is_some()
+    }
+
+    /// The page table entry for `path`, building the page on first use and
+    /// again once it or one of its includes changed.
+    fn page(&self, path: &Path) -> Result<Arc<PageEntry>, RspError> {
+        if let Some(page) = self.cached_page(path) {
+            if !self.check_changes.load(Ordering::SeqCst) || page.is_fresh() {
+                return Ok(page);
+            }
+        }
+        self.load_page(path)
+    }
+
+    fn cached_page(&self, path: &Path) -> Option<Arc<PageEntry>> {
+        // The server's paths are canonical already, unless a symlink or `..`
+        // is involved.
+        if let Some(page) = self.pages.read().unwrap().get(path) {
+            return Some(page.clone());
+        }
+        let canonical = path.canonicalize().ok()?;
+        self.pages.read().unwrap().get(&canonical).cloned()
+    }
+
+    /// Builds and loads the page at `path` and puts it in the page table.
+    fn load_page(&self, path: &Path) -> Result<Arc<PageEntry>, RspError> {
+        let canonical = path.canonicalize()?;
+        // Taken before reading, so a write in between is noticed next time.
+        let stamp = FileStamp::of(&canonical)?;
+        let content = std::fs::read_to_string(&canonical)?;
+        let (generated, hash, lib_path) = self.build(&content, Some(path))?;
+
+        // Includes were stamped as the generator read them.
+        let mut files = vec![(canonical.clone(), stamp)];
+        files.extend(
+            generated.source_map.files[1..]
+                .iter()
+                .filter_map(|file| Some((file.path.clone()?, file.stamp?))),
+        );
+        let previous = self.pages.read().unwrap().get(&canonical).cloned();
+        let library = match &previous {
+            // Only touched, or changed and changed back.
+            Some(page) if page.hash == hash => page.library.clone(),
+            _ => self.load(&generated, &hash, &lib_path)?,
+        };
+
+        let page = Arc::new(PageEntry {
+            files,
+            hash,
+            library,
+            source_map: generated.source_map,
+        });
+        self.pages.write().unwrap().insert(canonical, page.clone());
+        // The old version is unloaded once its last render is done.
+        if let Some(previous) = previous.filter(|p| p.hash != page.hash) {
+            self.loader
+                .unload(&self.compiler.get_lib_path(&previous.hash));
+        }
+        Ok(page)
+    }
+
+    /// Loads a compiled page. A stale library in the cache is built again
+    /// once.
+    fn load(
+        &self,
+        generated: &GeneratedCode,
+        hash: &str,
+        lib_path: &Path,
+    ) -> Result<Arc<LoadedLib>, RspError> {
+        let seen = modified(lib_path);
+        match self.loader.load(lib_path) {
+            Err(LoadError::Incompatible(_)) => {
+                let lib_path = self.rebuild(generated, hash, seen)?;
+                Ok(self.loader.load(&lib_path)?)
+            }
+            loaded => Ok(loaded?),
+        }
+    }
+
+    /// Generates a page's code and compiles it if it isn't cached yet.
+    /// Returns the code, its hash and the library path.
+    fn build(
+        &self,
+        rsp_content: &str,
+        path: Option<&Path>,
+    ) -> Result<(GeneratedCode, String, PathBuf), RspError> {
+        let docroot = self.docroot.lock().unwrap().clone();
+        let parsed = self.parser.parse(rsp_content).map_err(|e| match path {
+            Some(path) => e.with_file(&display_name(path, Some(&docroot))),
+            None => e,
+        })?;
+        let options = GenerateOptions {
+            path: path.map(Path::to_path_buf),
+            docroot: Some(docroot),
+        };
+        let generated = self.generator.generate_with_options(&parsed, options)?;
+
+        // The library is built from the generated code, so that is what the
+        // hash covers: it already inlines every include, and any change to
+        // the generator (a new prelude, say) gives pages a new cache entry.
+        // The ABI and runtime versions keep libraries from older builds of
+        // rsp from being picked up.
         let mut hasher = Sha256::new();
-        hasher.update(rsp_content.as_bytes());
+        hasher.update(ABI_VERSION.to_le_bytes());
+        hasher.update(rsp_runtime::VERSION.as_bytes());
+        hasher.update([u8::from(generated.needs_cargo)]);
+        for dependency in &generated.dependencies {
+            hasher.update(dependency.as_bytes());
+            hasher.update([0]);
+        }
+        hasher.update(generated.source.as_bytes());
         let hash = format!("{:x}", hasher.finalize());
 
-        let lib_path = if generated.needs_cargo {
+        let lib_path = self.compile(&generated, &hash)?;
+        Ok((generated, hash, lib_path))
+    }
+
+    /// Path of the compiled library for `hash`, building it if needed.
+    fn compile(&self, generated: &GeneratedCode, hash: &str) -> Result<PathBuf, RspError> {
+        let cached = self.compiler.get_lib_path(hash);
+        if cached.exists() {
+            return Ok(cached);
+        }
+        self.builds
+            .run(hash, || self.build_library(generated, hash))
+            .map_err(|e| RspError::Compile(e.with_source_map(&generated.source_map)))
+    }
+
+    /// Builds `hash` again over a stale library whose modification time was
+    /// `stale`. Requests that find the same stale library share one rebuild,
+    /// and one that comes in after it finished uses its result instead of
+    /// deleting it.
+    fn rebuild(
+        &self,
+        generated: &GeneratedCode,
+        hash: &str,
+        stale: Option<SystemTime>,
+    ) -> Result<PathBuf, RspError> {
+        let lib_path = self.compiler.get_lib_path(hash);
+        self.builds
+            .run(hash, || {
+                if lib_path.exists() && modified(&lib_path) != stale {
+                    return Ok(lib_path.clone());
+                }
+                match std::fs::remove_file(&lib_path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+                self.build_library(generated, hash)
+            })
+            .map_err(|e| RspError::Compile(e.with_source_map(&generated.source_map)))
+    }
+
+    fn build_library(
+        &self,
+        generated: &GeneratedCode,
+        hash: &str,
+    ) -> Result<PathBuf, CompileError> {
+        if generated.needs_cargo {
             let options = CompileOptions {
-                dependencies: generated.dependencies,
+                dependencies: generated.dependencies.clone(),
             };
             self.compiler
-                .compile_with_options(&generated.source, &hash, options)?
+                .compile_with_options(&generated.source, hash, options)
         } else {
-            self.compiler.compile(&generated.source, &hash)?
-        };
-
-        let mut loader = self.loader.lock().unwrap();
-        let (content, status_code, redirect, cookies, headers) =
-            loader.render_with_response(&lib_path)?;
-
-        Ok(RenderResult {
-            content,
-            status_code,
-            redirect,
-            cookies,
-            headers,
-        })
+            self.compiler.compile(&generated.source, hash)
+        }
     }
 
     pub fn render_file(&self, path: &Path) -> Result<RenderResult, RspError> {
-        let content = std::fs::read_to_string(path)?;
-        self.render(&content)
+        self.render_file_with_request(path, &RequestData::default())
     }
 
     pub fn render_file_with_body(&self, path: &Path, body: &str) -> Result<RenderResult, RspError> {
-        std::env::set_var("RSP_BODY", body);
-        self.render_file(path)
+        let request = RequestData {
+            body: body.to_string(),
+            ..RequestData::default()
+        };
+        self.render_file_with_request(path, &request)
+    }
+
+    /// Renders a page for one HTTP request; the page sees it as `req`.
+    pub fn render_file_with_request(
+        &self,
+        path: &Path,
+        request: &RequestData,
+    ) -> Result<RenderResult, RspError> {
+        let page = self.page(path)?;
+        let response = page
+            .library
+            .render(request)
+            .map_err(|e| render_error(e, &page.source_map))?;
+        Ok(response.into())
     }
 
     pub fn include(&self, relative_path: &str) -> Result<String, RspError> {
@@ -155,7 +483,7 @@ impl RspEngine {
         }
 
         let result = self.render_file(&full_path)?;
-        Ok(result.content)
+        Ok(String::from_utf8_lossy(&result.content).into_owned())
     }
 
     pub fn cache_dir(&self) -> &Path {
@@ -163,9 +491,104 @@ impl RspEngine {
     }
 
     pub fn unload_all(&self) {
-        if let Ok(mut loader) = self.loader.lock() {
-            loader.unload_all();
+        self.pages.write().unwrap().clear();
+        self.loader.unload_all();
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// The error for a failed render, with a panic pointed at its template line.
+fn render_error(error: LoadError, source_map: &SourceMap) -> RspError {
+    match error {
+        LoadError::Panic(panic) => RspError::Panic(Box::new(PanicReport::new(panic, source_map))),
+        e => RspError::Load(e),
+    }
+}
+
+pub const DEFAULT_MAX_BUILDS: usize = 2;
+
+/// Compilations started by `RspEngine`. Requests for a hash that is already
+/// being built wait for that build and share its result instead of starting
+/// their own, and at most `limit` builds run at once.
+struct Builds {
+    in_flight: Mutex<HashMap<String, Arc<Flight>>>,
+    running: Mutex<usize>,
+    slot_freed: Condvar,
+    limit: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Flight {
+    result: Mutex<Option<Result<PathBuf, CompileError>>>,
+    done: Condvar,
+}
+
+impl Builds {
+    fn new(limit: usize) -> Self {
+        Builds {
+            in_flight: Mutex::new(HashMap::new()),
+            running: Mutex::new(0),
+            slot_freed: Condvar::new(),
+            limit: AtomicUsize::new(limit),
+        }
+    }
+
+    fn run(
+        &self,
+        hash: &str,
+        build: impl FnOnce() -> Result<PathBuf, CompileError>,
+    ) -> Result<PathBuf, CompileError> {
+        let (flight, leader) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(hash) {
+                Some(flight) => (flight.clone(), false),
+                None => {
+                    let flight = Arc::new(Flight::default());
+                    in_flight.insert(hash.to_string(), flight.clone());
+                    (flight, true)
+                }
+            }
+        };
+
+        if !leader {
+            let mut result = flight.result.lock().unwrap();
+            while result.is_none() {
+                result = flight.done.wait(result).unwrap();
+            }
+            return result.clone().unwrap();
+        }
+
+        let result = {
+            let _slot = self.acquire();
+            // Waiters must be woken even if the build panics.
+            std::panic::catch_unwind(AssertUnwindSafe(build))
+                .unwrap_or_else(|_| Err(CompileError::Compile("the build panicked".to_string())))
+        };
+        *flight.result.lock().unwrap() = Some(result.clone());
+        flight.done.notify_all();
+        self.in_flight.lock().unwrap().remove(hash);
+        result
+    }
+
+    fn acquire(&self) -> BuildSlot<'_> {
+        let mut running = self.running.lock().unwrap();
+        while *running >= self.limit.load(Ordering::SeqCst) {
+            running = self.slot_freed.wait(running).unwrap();
         }
+        *running += 1;
+        BuildSlot(self)
+    }
+}
+
+struct BuildSlot<'a>(&'a Builds);
+
+impl Drop for BuildSlot<'_> {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.slot_freed.notify_one();
     }
 }
 
@@ -184,7 +607,7 @@ pub fn register_cleanup(engine: Arc<RspEngine>) {
         ctrlc::set_handler(move || {
             unsafe {
                 if let Some(ref engine) = ENGINE_TO_CLEANUP {
-                    let _ = engine.loader.lock().map(|mut l| l.unload_all());
+                    engine.unload_all();
                 }
             }
             std::process::exit(0);
@@ -192,3 +615,263 @@ pub fn register_cleanup(engine: Arc<RspEngine>) {
         .ok();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Barrier};
+
+    #[test]
+    fn test_builds_share_in_flight_results() {
+        let builds = Arc::new(Builds::new(1));
+        let started = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let builds = builds.clone();
+                let started = started.clone();
+                let barrier = barrier.clone();
+                let release_rx = release_rx.clone();
+                std::thread::spawn(move || {
+                    let hash = if i % 2 == 0 { "a" } else { "b" };
+                    barrier.wait();
+                    builds.run(hash, || {
+                        started.fetch_add(1, Ordering::SeqCst);
+                        release_rx.lock().unwrap().recv().unwrap();
+                        if hash == "a" {
+                            Ok(PathBuf::from("liba.so"))
+                        } else {
+                            Err(CompileError::Compile("broken".to_string()))
+                        }
+                    })
+                })
+            })
+            .collect();
+
+        // Hold the builds until all four threads of each hash share its
+        // flight: the map's reference plus one per thread.
+        let joined = |hash: &str| {
+            let in_flight = builds.in_flight.lock().unwrap();
+            in_flight.get(hash).map(Arc::strong_count) == Some(5)
+        };
+        while !(joined("a") && joined("b")) {
+            std::thread::yield_now();
+        }
+        release_tx.send(()).unwrap();
+        release_tx.send(()).unwrap();
+
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+        for (i, result) in results.iter().enumerate() {
+            match result {
+                Ok(path) => assert!(i % 2 == 0 && path == Path::new("liba.so")),
+                Err(CompileError::Compile(message)) => assert!(i % 2 == 1 && message == "broken"),
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+        assert!(builds.in_flight.lock().unwrap().is_empty());
+        assert_eq!(*builds.running.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_file_stamp_notices_changes() {
+        let path = std::env::temp_dir().join(format!("rsp-stamp-{}.rsp", std::process::id()));
+        std::fs::write(&path, "<p>one</p>").unwrap();
+        let stamp = FileStamp::of(&path).unwrap();
+        assert_eq!(FileStamp::of(&path).unwrap(), stamp);
+
+        std::fs::write(&path, "<p>three</p>").unwrap();
+        assert_ne!(FileStamp::of(&path).unwrap(), stamp);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(FileStamp::of(&path).is_err());
+    }
+
+    fn page_entry(engine: &RspEngine, path: &Path) -> Arc<PageEntry> {
+        engine.cached_page(path).unwrap()
+    }
+
+    fn render_text(engine: &RspEngine, path: &Path) -> String {
+        String::from_utf8(engine.render_file(path).unwrap().content).unwrap()
+    }
+
+    #[test]
+    fn test_page_table() {
+        let dir = std::env::temp_dir().join(format!("rsp-pages-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let engine = RspEngine::new(dir.join(".rspcache")).unwrap();
+        engine.set_docroot(dir.clone());
+        let page = dir.join("page.rsp");
+        let header = dir.join("header.rsp");
+        std::fs::write(&header, "<h1>v1</h1>").unwrap();
+        std::fs::write(&page, "<%@ include \"header.rsp\" %>page").unwrap();
+
+        assert_eq!(render_text(&engine, &page), "<h1>v1</h1>page");
+        let first = page_entry(&engine, &page);
+        assert!(Arc::ptr_eq(&first, &page_entry(&engine, &page)));
+
+        // Touched without changing: looked at again, same library.
+        let later = SystemTime::now() + std::time::Duration::from_secs(10);
+        std::fs::File::options()
+            .write(true)
+            .open(&page)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(render_text(&engine, &page), "<h1>v1</h1>page");
+        let touched = page_entry(&engine, &page);
+        assert!(!Arc::ptr_eq(&first, &touched));
+        assert!(Arc::ptr_eq(&first.library, &touched.library));
+
+        // An included file changed: rebuilt.
+        std::fs::write(&header, "<h1>v2!</h1>").unwrap();
+        assert_eq!(render_text(&engine, &page), "<h1>v2!</h1>page");
+        let changed = page_entry(&engine, &page);
+        assert!(!Arc::ptr_eq(&touched.library, &changed.library));
+        let old = Arc::downgrade(&touched.library);
+        drop((first, touched));
+        assert!(old.upgrade().is_none(), "the old library is still loaded");
+
+        // Without checks, the page stays as it is until compiled again.
+        engine.set_check_changes(false);
+        std::fs::write(&header, "<h1>v3!!</h1>").unwrap();
+        assert_eq!(render_text(&engine, &page), "<h1>v2!</h1>page");
+        assert!(Arc::ptr_eq(&changed, &page_entry(&engine, &page)));
+        let includes = engine.compile_file(&page).unwrap();
+        assert_eq!(includes, vec![header.canonicalize().unwrap()]);
+        assert_eq!(render_text(&engine, &page), "<h1>v3!!</h1>page");
+    }
+
+    #[test]
+    fn test_stale_library_is_rebuilt() {
+        let dir = std::env::temp_dir().join(format!("rsp-stale-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let page = dir.join("page.rsp");
+        std::fs::write(&page, "<p>fresh</p>").unwrap();
+
+        let engine = RspEngine::new(dir.join(".rspcache")).unwrap();
+        engine.compile_file(&page).unwrap();
+        let lib_path = engine
+            .compiler
+            .get_lib_path(&page_entry(&engine, &page).hash);
+        drop(engine);
+
+        // What an older rsp left in the cache under the same hash.
+        let parsed = Parser::new().parse("<p>stale</p>").unwrap();
+        let generated = Generator::new()
+            .generate_with_options(&parsed, GenerateOptions::default())
+            .unwrap();
+        let source = generated
+            .source
+            .replace(&format!("abi_version: {},", ABI_VERSION), "abi_version: 2,");
+        let stale = Compiler::new(dir.join("stale"))
+            .compile(&source, "stale")
+            .unwrap();
+        std::fs::rename(&stale, &lib_path).unwrap();
+        assert!(matches!(
+            Loader::new().load(&lib_path),
+            Err(LoadError::Incompatible(_))
+        ));
+
+        let engine = RspEngine::new(dir.join(".rspcache")).unwrap();
+        assert_eq!(render_text(&engine, &page), "<p>fresh</p>");
+        assert!(Loader::new().load(&lib_path).is_ok());
+    }
+
+    #[test]
+    fn test_panicking_page() {
+        let dir = std::env::temp_dir().join(format!("rsp-panic-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let engine = RspEngine::new(dir.join(".rspcache")).unwrap();
+        engine.set_docroot(dir.clone());
+        let page = dir.join("panic.rsp");
+        let other = dir.join("ok.rsp");
+        std::fs::write(
+            &page,
+            "<p>before</p>\n<% let items: Vec<u32> = Vec::new(); %>\n<%= items[2] %>",
+        )
+        .unwrap();
+        std::fs::write(&other, "<p>ok</p>").unwrap();
+
+        // The panic stays in its render: the same page fails the same way
+        // the next time, and other pages keep working.
+        for _ in 0..2 {
+            let error = match engine.render_file(&page) {
+                Err(error) => error,
+                Ok(_) => panic!("expected a panic"),
+            };
+            assert!(
+                error.to_string().ends_with(
+                    "panic.rsp:3:10: index out of bounds: the len is 0 but the index is 2"
+                ),
+                "{}",
+                error
+            );
+            let RspError::Panic(report) = error else {
+                panic!("expected a panic, got {}", error);
+            };
+            let location = report.location.as_ref().unwrap();
+            assert_eq!((location.line, location.column), (3, 10));
+            assert!(location.file.ends_with("panic.rsp"), "{}", location.file);
+            assert!(
+                report.message.contains("index out of bounds"),
+                "{}",
+                report.message
+            );
+            assert_eq!(report.excerpt[2], (3, "<%= items[2] %>".to_string()));
+            assert_eq!(render_text(&engine, &other), "<p>ok</p>");
+        }
+    }
+
+    #[test]
+    fn test_renders_see_only_their_request() {
+        let dir = std::env::temp_dir().join(format!("rsp-requests-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let engine = RspEngine::new(dir.join(".rspcache")).unwrap();
+        let page = dir.join("page.rsp");
+        std::fs::write(
+            &page,
+            format!(
+                "<%@ dep rsp-runtime = {{ path = {:?} }} %>\
+                 <%= req.method() %> <%= req.path() %>|<%= req.get[\"q\"] %>|\
+                 <%= req.post[\"p\"] %>|<%= req.cookie[\"sid\"] %>|\
+                 <%= req.ua[\"x-test\"] %>|<%= req.route[\"id\"] %>",
+                concat!(env!("CARGO_MANIFEST_DIR"), "/runtime")
+            ),
+        )
+        .unwrap();
+
+        let first = RequestData {
+            method: "POST".to_string(),
+            path: "/page.rsp".to_string(),
+            query: "q=1".to_string(),
+            headers: vec![
+                ("Cookie".to_string(), "sid=abc".to_string()),
+                ("X-Test".to_string(), "yes".to_string()),
+            ],
+            body: "p=2".to_string(),
+            route: HashMap::from([("id".to_string(), "7".to_string())]),
+            error: None,
+        };
+        let render = |request: &RequestData| {
+            let result = engine.render_file_with_request(&page, request).unwrap();
+            String::from_utf8(result.content).unwrap()
+        };
+        assert_eq!(render(&first), "POST /page.rsp|1|2|abc|yes|7");
+
+        let second = RequestData {
+            query: "q=3".to_string(),
+            ..RequestData::default()
+        };
+        assert_eq!(render(&second), "GET /|3||||");
+        assert_eq!(render(&first), "POST /page.rsp|1|2|abc|yes|7");
+    }
+}
@@ -0,0 +1,307 @@
+use crate::compiler::{CompileError, Diagnostic};
+use crate::engine::{PanicReport, RspError};
+use crate::generator::GenerateError;
+use crate::parser::ParseError;
+use rsp_runtime::escape_html;
+
+const STYLE: &str = r#"
+body { font-family: system-ui, sans-serif; margin: 0; background: #f5f5f5; color: #333; }
+header { background: #b71c1c; color: white; padding: 24px 40px; }
+header h1 { margin: 0; font-size: 24px; }
+header p { margin: 6px 0 0; opacity: 0.85; font-family: monospace; }
+main { padding: 24px 40px; max-width: 1100px; }
+.error { background: white; border-radius: 8px; padding: 20px; margin-bottom: 20px; box-shadow: 0 1px 3px rgba(0,0,0,0.1); }
+.error h2 { margin: 0 0 8px; font-size: 17px; color: #b71c1c; font-family: monospace; }
+.location { color: #666; font-family: monospace; margin-bottom: 12px; }
+table.code { border-collapse: collapse; width: 100%; font-family: monospace; font-size: 13px; background: #1e1e1e; color: #d4d4d4; border-radius: 6px; overflow: hidden; }
+table.code td { padding: 1px 10px; white-space: pre; }
+table.code td.line { color: #858585; text-align: right; width: 1%; user-select: none; }
+table.code tr.hit { background: #5a1d1d; }
+table.code tr.caret td { color: #f48771; }
+ul.notes { margin: 10px 0 0; padding-left: 20px; font-family: monospace; font-size: 13px; color: #555; }
+details { margin-top: 12px; }
+summary { cursor: pointer; color: #666; }
+pre { background: #1e1e1e; color: #d4d4d4; padding: 16px; border-radius: 6px; overflow-x: auto; }
+"#;
+
+/// Renders the development error page shown when a page fails to parse,
+/// compile or load.
+pub fn render_error_page(error: &RspError, path: &str) -> String {
+    let body = match error {
+        RspError::Compile(CompileError::Diagnostics(diagnostics)) => diagnostics
+            .iter()
+            .map(render_diagnostic)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        RspError::Parse(e) | RspError::Generate(GenerateError::Parse { error: e, .. }) => {
+            render_parse_error(e)
+        }
+        RspError::Panic(report) => render_panic(report),
+        _ => format!(
+            "<div class=\"error\"><pre>{}</pre></div>",
+            escape_html(&error.to_string())
+        ),
+    };
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <title>{kind} - RSP</title>
+    <style>{style}</style>
+</head>
+<body>
+    <header>
+        <h1>{kind}</h1>
+        <p>{path}</p>
+    </header>
+    <main>
+{body}
+    </main>
+</body>
+</html>
+"#,
+        kind = error.kind(),
+        style = STYLE,
+        path = escape_html(path),
+        body = body,
+    )
+}
+
+fn render_parse_error(error: &ParseError) -> String {
+    let location = error.location();
+    let mut html = format!(
+        "<div class=\"error\">\n<h2>error: {}</h2>\n<div class=\"location\">{}:{}:{}</div>\n",
+        escape_html(&error.message()),
+        escape_html(location.file.as_deref().unwrap_or("<template>")),
+        location.line,
+        location.column
+    );
+    let caret = (location.column, location.width, String::new());
+    html.push_str(&code_table(&location.excerpt, location.line, Some(caret)));
+    if let Some(help) = error.help() {
+        html.push_str(&format!(
+            "<ul class=\"notes\">\n<li>help: {}</li>\n</ul>\n",
+            escape_html(&help)
+        ));
+    }
+    html.push_str("</div>");
+    html
+}
+
+fn render_panic(report: &PanicReport) -> String {
+    let mut html = format!(
+        "<div class=\"error\">\n<h2>panicked: {}</h2>\n",
+        escape_html(&report.message)
+    );
+    match &report.location {
+        Some(location) => {
+            html.push_str(&format!(
+                "<div class=\"location\">{}:{}:{}</div>\n",
+                escape_html(&location.file),
+                location.line,
+                location.column
+            ));
+            let caret = (location.column, 1, "panicked here".to_string());
+            html.push_str(&code_table(&report.excerpt, location.line, Some(caret)));
+        }
+        None => html.push_str(&format!(
+            "<div class=\"location\">{}</div>\n",
+            escape_html(&report.origin)
+        )),
+    }
+    html.push_str("</div>");
+    html
+}
+
+fn render_diagnostic(diagnostic: &Diagnostic) -> String {
+    let title = match &diagnostic.code {
+        Some(code) => format!("{}[{}]: {}", diagnostic.level, code, diagnostic.message),
+        None => format!("{}: {}", diagnostic.level, diagnostic.message),
+    };
+    let mut html = format!("<div class=\"error\">\n<h2>{}</h2>\n", escape_html(&title));
+
+    match &diagnostic.location {
+        Some(location) => {
+            html.push_str(&format!(
+                "<div class=\"location\">{}:{}:{}</div>\n",
+                escape_html(&location.file),
+                location.line,
+                location.column
+            ));
+            let caret = diagnostic.generated.map(|span| {
+                (
+                    location.column,
+                    span.column_end.saturating_sub(span.column).max(1),
+                    diagnostic.label.clone().unwrap_or_default(),
+                )
+            });
+            html.push_str(&code_table(&diagnostic.excerpt, location.line, caret));
+        }
+        None => {
+            html.push_str(&format!(
+                "<pre>{}</pre>\n",
+                escape_html(diagnostic.rendered.trim_end())
+            ));
+        }
+    }
+
+    if !diagnostic.notes.is_empty() {
+        html.push_str("<ul class=\"notes\">\n");
+        for note in &diagnostic.notes {
+            html.push_str(&format!("<li>{}</li>\n", escape_html(note)));
+        }
+        html.push_str("</ul>\n");
+    }
+
+    if let Some(span) = diagnostic.generated {
+        html.push_str("<details>\n<summary>Generated Rust</summary>\n");
+        html.push_str(&code_table(&diagnostic.generated_excerpt, span.line, None));
+        html.push_str("</details>\n");
+    }
+
+    html.push_str("</div>");
+    html
+}
+
+/// Numbered source lines with `highlight` marked, optionally followed by a
+/// caret row `(column, width, label)` under the highlighted line.
+fn code_table(
+    lines: &[(usize, String)],
+    highlight: usize,
+    caret: Option<(usize, usize, String)>,
+) -> String {
+    let mut html = String::from("<table class=\"code\">\n");
+    for (number, text) in lines {
+        let class = if *number == highlight {
+            " class=\"hit\""
+        } else {
+            ""
+        };
+        html.push_str(&format!(
+            "<tr{}><td class=\"line\">{}</td><td>{}</td></tr>\n",
+            class,
+            number,
+            escape_html(text)
+        ));
+        if *number == highlight {
+            if let Some((column, width, label)) = &caret {
+                html.push_str(&format!(
+                    "<tr class=\"caret\"><td class=\"line\"></td><td>{}{} {}</td></tr>\n",
+                    " ".repeat(column.saturating_sub(1)),
+                    "^".repeat(*width),
+                    escape_html(label)
+                ));
+            }
+        }
+    }
+    html.push_str("</table>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::GeneratedSpan;
+    use crate::parser::Parser;
+    use crate::source_map::TemplateLocation;
+
+    fn location(line: usize, column: usize) -> TemplateLocation {
+        TemplateLocation {
+            source: 0,
+            file: "<script>.rsp".to_string(),
+            line,
+            column,
+        }
+    }
+
+    fn assert_escaped(html: &str) {
+        assert!(!html.contains("<script>"), "{}", html);
+        assert!(html.contains("&lt;script&gt;"), "{}", html);
+    }
+
+    #[test]
+    fn test_parse_error_page() {
+        let error = Parser::new()
+            .parse("<script>alert(1)</script>\n<%= %>")
+            .unwrap_err();
+        let html = render_error_page(&RspError::Parse(error), "/<script>.rsp");
+        assert!(html.contains("<title>Parse error - RSP</title>"));
+        assert!(html.contains("<div class=\"location\">&lt;template&gt;:2:"));
+        assert!(
+            html.contains("<tr class=\"hit\"><td class=\"line\">2</td><td>&lt;%= %&gt;</td></tr>")
+        );
+        assert!(html
+            .contains("<td class=\"line\">1</td><td>&lt;script&gt;alert(1)&lt;/script&gt;</td>"));
+        assert_escaped(&html);
+    }
+
+    #[test]
+    fn test_diagnostics_page() {
+        let diagnostic = Diagnostic {
+            level: "error".to_string(),
+            code: Some("E0425".to_string()),
+            message: "cannot find value `<script>` in this scope".to_string(),
+            label: Some("not found".to_string()),
+            generated: Some(GeneratedSpan {
+                line: 40,
+                column: 9,
+                column_end: 14,
+            }),
+            location: Some(location(2, 5)),
+            snippet: None,
+            excerpt: vec![(1, "<p>".to_string()), (2, "<%= <script> %>".to_string())],
+            generated_excerpt: vec![(40, "    out(\"<script>\");".to_string())],
+            notes: vec!["help: a local variable <script> exists".to_string()],
+            rendered: String::new(),
+        };
+        let unmapped = Diagnostic {
+            location: None,
+            generated: None,
+            rendered: "error: linking with <script> failed\n".to_string(),
+            ..diagnostic.clone()
+        };
+        let error = RspError::Compile(CompileError::Diagnostics(vec![diagnostic, unmapped]));
+        let html = render_error_page(&error, "/page.rsp");
+
+        assert!(html.contains("<title>Compile error - RSP</title>"));
+        assert!(html
+            .contains("<h2>error[E0425]: cannot find value `&lt;script&gt;` in this scope</h2>"));
+        assert!(html.contains("<div class=\"location\">&lt;script&gt;.rsp:2:5</div>"));
+        assert!(html.contains("<td>&lt;%= &lt;script&gt; %&gt;</td>"));
+        assert!(html.contains("<td>    ^^^^^ not found</td>"));
+        assert!(html.contains("<td>    out(&quot;&lt;script&gt;&quot;);</td>"));
+        assert!(html.contains("<li>help: a local variable &lt;script&gt; exists</li>"));
+        assert!(html.contains("<pre>error: linking with &lt;script&gt; failed</pre>"));
+        assert_escaped(&html);
+    }
+
+    #[test]
+    fn test_panic_page() {
+        let report = PanicReport {
+            message: "bad <script> input".to_string(),
+            origin: "src/lib.rs:10:5".to_string(),
+            location: Some(location(1, 4)),
+            excerpt: vec![(1, "<% panic!(\"<script>\"); %>".to_string())],
+        };
+        let html = render_error_page(&RspError::Panic(Box::new(report.clone())), "/page.rsp");
+        assert!(html.contains("<title>Runtime error - RSP</title>"));
+        assert!(html.contains("<h2>panicked: bad &lt;script&gt; input</h2>"));
+        assert!(html.contains("<div class=\"location\">&lt;script&gt;.rsp:1:4</div>"));
+        assert!(html.contains("<td>   ^ panicked here</td>"));
+        assert_escaped(&html);
+
+        // A panic outside the page's code only has Rust's location.
+        let report = PanicReport {
+            location: None,
+            excerpt: Vec::new(),
+            origin: "<script>/lib.rs:1:1".to_string(),
+            ..report
+        };
+        let html = render_error_page(&RspError::Panic(Box::new(report)), "/page.rsp");
+        assert!(html.contains("<div class=\"location\">&lt;script&gt;/lib.rs:1:1</div>"));
+        assert!(!html.contains("<table"));
+        assert_escaped(&html);
+    }
+}
@@ -1,10 +1,58 @@
-use crate::parser::{ParsedTemplate, Token};
+use crate::loader::ABI_VERSION;
+use crate::parser::{ParseError, ParsedTemplate, Parser, Token};
+use crate::source_map::{FileStamp, Section, SourceFile, SourceMap};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
 
 #[derive(Debug, Clone, Default)]
 pub struct GeneratedCode {
     pub source: String,
     pub needs_cargo: bool,
     pub dependencies: Vec<String>,
+    /// Every file inlined through `<%@ include %>` or `<%@ extends %>`, in the
+    /// order first seen.
+    pub includes: Vec<PathBuf>,
+    /// Maps lines of `source` back to the template files.
+    pub source_map: SourceMap,
+}
+
+/// Sentinel lines marking where the generated sections go in the page skeleton.
+const IMPORTS_SECTION: &str = "\u{0}imports";
+const STATIC_SECTION: &str = "\u{0}static";
+const RENDER_SECTION: &str = "\u{0}render";
+
+#[derive(Debug, Clone, Default)]
+pub struct GenerateOptions {
+    /// File the template was read from. Includes are resolved relative to it first.
+    pub path: Option<PathBuf>,
+    /// Fallback root for includes, and the root for `/`-prefixed include paths.
+    pub docroot: Option<PathBuf>,
+}
+
+#[derive(Error, Debug)]
+pub enum GenerateError {
+    #[error("Invalid directive: {0}")]
+    InvalidDirective(String),
+    #[error("Included file not found: {0}")]
+    IncludeNotFound(String),
+    #[error("Layout not found: {0}")]
+    LayoutNotFound(String),
+    #[error("Template includes or extends itself: {0}")]
+    Cycle(String),
+    #[error("Only one <%@ extends %> is allowed per template")]
+    MultipleExtends,
+    #[error("Block `{name}` is not defined in layout {layout}")]
+    UnknownBlock { name: String, layout: String },
+    #[error("Block `{0}` is defined twice")]
+    DuplicateBlock(String),
+    #[error("Block `{0}` is missing <%@ endblock %>")]
+    UnclosedBlock(String),
+    #[error("<%@ endblock %> without a matching <%@ block %>")]
+    UnexpectedEndblock,
+    #[error("{error}")]
+    Parse { path: PathBuf, error: ParseError },
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
 pub struct Generator;
@@ -14,122 +62,185 @@ impl Generator {
         Generator
     }
 
-    pub fn generate_full_source(&self, parsed: &ParsedTemplate) -> GeneratedCode {
-        let mut imports = String::new();
-        let mut static_code = String::new();
-        let mut render_code = String::new();
+    pub fn generate_full_source(
+        &self,
+        parsed: &ParsedTemplate,
+    ) -> Result<GeneratedCode, GenerateError> {
+        self.generate_with_options(parsed, GenerateOptions::default())
+    }
+
+    pub fn generate_with_options(
+        &self,
+        parsed: &ParsedTemplate,
+        options: GenerateOptions,
+    ) -> Result<GeneratedCode, GenerateError> {
+        let docroot = options.docroot.as_deref();
+        let mut stack = Vec::new();
+        let canonical = options
+            .path
+            .as_ref()
+            .map(|path| path.canonicalize().unwrap_or_else(|_| path.clone()));
+        stack.extend(canonical.clone());
+        let mut files = vec![SourceFile {
+            name: options
+                .path
+                .as_deref()
+                .map(|path| display_name(path, docroot))
+                .unwrap_or_else(|| "<template>".to_string()),
+            path: canonical,
+            content: parsed.source.clone(),
+            stamp: None,
+        }];
+
+        let tokens = resolve_tokens(
+            &parsed.tokens,
+            options.path.as_deref(),
+            docroot,
+            &mut stack,
+            &mut files,
+        )?;
+        let tokens = strip_block_markers(tokens)?;
+
+        let mut imports = Section::default();
+        let mut static_code = Section::default();
+        let mut render_code = Section::default();
         let mut needs_cargo = false;
         let mut dependencies = Vec::new();
         let mut has_lazy = false;
         let mut has_request = false;
         let mut has_escape_html = false;
-        let mut has_response_control = false;
+        let mut has_safe_html = false;
+        let raw_sources = autoescape_off(&tokens)?;
 
-        for dec in &parsed.declarations {
-            if dec.contains("Lazy<") || dec.contains("once_cell") {
-                has_lazy = true;
-            }
-            if dec.contains("escape_html") {
-                has_escape_html = true;
-            }
-            static_code.push_str(&format!("{}\n", dec));
-        }
+        let mut declarations: Vec<&str> = Vec::new();
 
-        for token in &parsed.tokens {
+        for token in &tokens {
             match token {
-                Token::Text(text) => {
+                Token::Text(text, span) => {
                     let escaped = escape_string(text);
-                    render_code.push_str(&format!("    output.push_str(\"{}\");\n", escaped));
+                    render_code.push_mapped("    __rsp_echo(\"", "", &escaped, "\");", *span);
                 }
-                Token::Expression(expr) => {
+                Token::Expression(expr, span) | Token::RawExpression(expr, span) => {
                     if expr.contains("req()") || expr.contains("req.") {
                         has_request = true;
                     }
                     if expr.contains("escape_html") {
                         has_escape_html = true;
                     }
-                    if expr.contains("header(")
-                        || expr.contains("header_url(")
-                        || expr.contains("SetCookie(")
-                        || expr.contains("CleanCookie(")
-                    {
-                        has_response_control = true;
+                    if expr.contains("SafeHtml") {
+                        has_safe_html = true;
+                    }
+                    let escape = matches!(token, Token::Expression(..))
+                        && !raw_sources.contains(&span.source);
+                    if escape {
+                        render_code.push_mapped(
+                            "    (&__RspOutput(&(",
+                            "",
+                            expr,
+                            "))).__rsp_write();",
+                            *span,
+                        );
+                    } else {
+                        render_code.push_mapped(
+                            "    __rsp_echo(&format!(\"{}\", ",
+                            "",
+                            expr,
+                            "));",
+                            *span,
+                        );
                     }
-                    render_code.push_str(&format!(
-                        "    output.push_str(&format!(\"{{}}\", {}));\n",
-                        expr
-                    ));
                 }
-                Token::Code(code_block) => {
+                Token::Code(code_block, span) => {
                     if code_block.contains("req()") || code_block.contains("req.") {
                         has_request = true;
                     }
                     if code_block.contains("escape_html") {
                         has_escape_html = true;
                     }
-                    if code_block.contains("header(")
-                        || code_block.contains("header_url(")
-                        || code_block.contains("SetCookie(")
-                        || code_block.contains("CleanCookie(")
-                    {
-                        has_response_control = true;
-                    }
-                    for line in code_block.lines() {
-                        render_code.push_str(&format!("    {}\n", line));
+                    if code_block.contains("SafeHtml") {
+                        has_safe_html = true;
                     }
+                    render_code.push_mapped("    ", "    ", code_block, "", *span);
                 }
-                Token::Directive(directive) => {
+                Token::Directive(directive, span) => {
                     let directive = directive.trim();
 
                     if directive.starts_with("use ") {
                         let use_stmt = directive.trim_start_matches("use ").trim();
-                        if !use_stmt.ends_with(';') {
-                            imports.push_str(&format!("use {};\n", use_stmt));
+                        let line = if !use_stmt.ends_with(';') {
+                            format!("use {};", use_stmt)
                         } else {
-                            imports.push_str(&format!("{}\n", directive));
+                            directive.to_string()
+                        };
+                        if !imports.contains(&format!("{}\n", line)) {
+                            imports.push_mapped("", "", &line, "", *span);
                         }
                     } else if directive.starts_with("dep ") {
                         needs_cargo = true;
                         let dep = directive.trim_start_matches("dep ").trim();
-                        dependencies.push(dep.to_string());
+                        push_dependency(&mut dependencies, dep.to_string());
                     } else if directive.starts_with("once_cell") {
                         has_lazy = true;
                         needs_cargo = true;
-                        dependencies.push("once_cell = \"1\"".to_string());
+                        push_dependency(&mut dependencies, "once_cell = \"1\"".to_string());
                     } else if directive.starts_with("rusqlite") {
                         needs_cargo = true;
-                        dependencies.push(
+                        push_dependency(
+                            &mut dependencies,
                             "rusqlite = { version = \"0.32\", features = [\"bundled\"] }"
                                 .to_string(),
                         );
                     }
                 }
-                Token::Declaration(_) => {}
+                Token::Declaration(dec, span) => {
+                    // The same header included twice must not declare its statics twice.
+                    if declarations.contains(&dec.as_str()) {
+                        continue;
+                    }
+                    declarations.push(dec);
+                    if dec.contains("Lazy<") || dec.contains("once_cell") {
+                        has_lazy = true;
+                    }
+                    if dec.contains("escape_html") {
+                        has_escape_html = true;
+                    }
+                    if dec.contains("SafeHtml") {
+                        has_safe_html = true;
+                    }
+                    static_code.push_mapped("", "", dec, "", *span);
+                }
             }
         }
 
-        if has_request || has_response_control {
-            imports.insert_str(
-                0,
-                "use rsp_runtime::{Request, Params, Cookies, Headers, escape_html};\n",
+        if has_request {
+            imports.prepend_unmapped(
+                "use rsp_runtime::{Request, Params, Cookies, Headers, SafeHtml, escape_html};\n",
             );
             needs_cargo = true;
-        } else if has_escape_html {
-            imports.insert_str(0, "use rsp_runtime::escape_html;\n");
+        } else if has_escape_html || has_safe_html {
+            imports.prepend_unmapped("use rsp_runtime::{SafeHtml, escape_html};\n");
             needs_cargo = true;
         }
 
         if has_lazy && !imports.contains("use once_cell") {
-            imports.insert_str(0, "use once_cell::sync::Lazy;\n");
+            imports.prepend_unmapped("use once_cell::sync::Lazy;\n");
         }
 
-        let request_init = if has_request || has_response_control {
-            "    let req = Request::new();\n    let _ = &req;\n"
-        } else {
-            ""
-        };
+        let mut request_init = String::new();
+        if has_request {
+            request_init.push_str(concat!(
+                "    let request = if request.is_null() {\n",
+                "        &[][..]\n",
+                "    } else {\n",
+                "        unsafe { std::slice::from_raw_parts(request, request_len) }\n",
+                "    };\n",
+                "    rsp_runtime::set_request(Request::from_bytes(request));\n",
+                "    let req = rsp_runtime::req();\n",
+                "    let _ = &req;\n",
+            ));
+        }
 
-        let source = format!(
+        let skeleton = format!(
             r#"#![allow(unused)]
 use std::os::raw::c_char;
 use std::ffi::CString;
@@ -138,8 +249,110 @@ use std::cell::RefCell;
 thread_local! {{
     static STATUS_CODE: RefCell<u16> = RefCell::new(200);
     static REDIRECT: RefCell<Option<String>> = RefCell::new(None);
-    static COOKIES: RefCell<Vec<(String, String, i64)>> = RefCell::new(Vec::new());
+    static COOKIES: RefCell<Vec<String>> = RefCell::new(Vec::new());
     static HEADERS: RefCell<Vec<(String, String)>> = RefCell::new(Vec::new());
+    static PANIC: RefCell<Option<String>> = RefCell::new(None);
+    static RENDERING: std::cell::Cell<bool> = std::cell::Cell::new(false);
+    static OUTPUT: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+    // Where in OUTPUT each open `ob_start()` buffer begins, innermost last.
+    static BUFFERS: RefCell<Vec<usize>> = RefCell::new(Vec::new());
+    static SINK: std::cell::Cell<*const RspSink> = std::cell::Cell::new(std::ptr::null());
+}}
+
+// Where `flush()` sends output when the server streams the response. Must
+// match `Sink` in the server's loader.
+#[repr(C)]
+struct RspSink {{
+    ctx: *mut std::os::raw::c_void,
+    write: extern "C" fn(*mut std::os::raw::c_void, *const u8, usize),
+}}
+
+fn __rsp_echo(text: &str) {{
+    OUTPUT.with(|o| o.borrow_mut().extend_from_slice(text.as_bytes()));
+}}
+
+fn write_bytes(bytes: &[u8]) {{
+    OUTPUT.with(|o| o.borrow_mut().extend_from_slice(bytes));
+}}
+
+// Sends everything written so far to the client. The first flush also sends
+// the status and headers, so changes to them after that are lost. Output
+// inside an `ob_start()` buffer stays until the buffer ends. Does nothing
+// when the response is buffered.
+fn flush() {{
+    let sink = SINK.with(|s| s.get());
+    if sink.is_null() {{
+        return;
+    }}
+    let chunk: Vec<u8> = OUTPUT.with(|o| {{
+        let mut o = o.borrow_mut();
+        let end = BUFFERS.with(|b| {{
+            let mut b = b.borrow_mut();
+            let end = b.first().copied().unwrap_or(o.len());
+            b.iter_mut().for_each(|start| *start -= end);
+            end
+        }});
+        o.drain(..end).collect()
+    }});
+    let sink = unsafe {{ &*sink }};
+    (sink.write)(sink.ctx, chunk.as_ptr(), chunk.len());
+}}
+
+// Starts capturing output instead of sending it. Buffers nest.
+fn ob_start() {{
+    let start = OUTPUT.with(|o| o.borrow().len());
+    BUFFERS.with(|b| b.borrow_mut().push(start));
+}}
+
+// Ends the innermost buffer and returns what it captured as text; empty if
+// no buffer is open. Bytes that aren't UTF-8, e.g. from `write_bytes()`,
+// become U+FFFD; use `ob_get_clean_bytes()` for binary output.
+fn ob_get_clean() -> String {{
+    String::from_utf8_lossy(&ob_get_clean_bytes()).into_owned()
+}}
+
+// Like `ob_get_clean()`, but returns the captured bytes as they are.
+fn ob_get_clean_bytes() -> Vec<u8> {{
+    match BUFFERS.with(|b| b.borrow_mut().pop()) {{
+        Some(start) => OUTPUT.with(|o| o.borrow_mut().split_off(start)),
+        None => Vec::new(),
+    }}
+}}
+
+// Ends the innermost buffer, passing what it captured on to the enclosing
+// buffer or the response. Returns false if no buffer is open.
+fn ob_end_flush() -> bool {{
+    BUFFERS.with(|b| b.borrow_mut().pop()).is_some()
+}}
+
+// Number of open `ob_start()` buffers.
+fn ob_get_level() -> usize {{
+    BUFFERS.with(|b| b.borrow().len())
+}}
+
+// Unwinds out of the page without counting as a panic.
+struct __RspExit;
+
+// Ends the page here, keeping its output, status, headers and cookies.
+fn exit() -> ! {{
+    halt(true)
+}}
+
+// Ends the page here with status `code`.
+fn exit_with(code: u16) -> ! {{
+    header(code);
+    halt(true)
+}}
+
+// Ends the page here; with `keep_output` false, output not yet flushed is
+// dropped, e.g. after `header_url()`.
+fn halt(keep_output: bool) -> ! {{
+    if !keep_output {{
+        OUTPUT.with(|o| o.borrow_mut().clear());
+        BUFFERS.with(|b| b.borrow_mut().clear());
+    }}
+    // Unlike `panic!`, this skips the panic hook, so no panic is recorded.
+    std::panic::resume_unwind(Box::new(__RspExit))
 }}
 
 fn header(code: u16) {{
@@ -152,79 +365,194 @@ fn header_url(url: &str) {{
 }}
 
 fn SetCookie(name: &str, value: &str, max_age: i64) {{
-    COOKIES.with(|c| c.borrow_mut().push((name.to_string(), value.to_string(), max_age)));
+    cookie(name, value).max_age(max_age);
 }}
 
 fn CleanCookie(name: &str) {{
-    COOKIES.with(|c| c.borrow_mut().push((name.to_string(), "".to_string(), -1)));
+    cookie(name, "").max_age(0);
+}}
+{}{}
+fn set_header(name: &str, value: &str) {{
+    HEADERS.with(|h| {{
+        let mut h = h.borrow_mut();
+        h.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        h.push((name.to_string(), value.to_string()));
+    }});
 }}
 
+fn remove_header(name: &str) {{
+    HEADERS.with(|h| h.borrow_mut().retain(|(n, _)| !n.eq_ignore_ascii_case(name)));
+}}
+
+fn content_type(mime: &str) {{
+    set_header("Content-Type", mime);
+}}
+
+// Fields handed to the server are tab-separated, one record per line.
+fn __rsp_field(s: &str) -> String {{
+    s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}}
+
+{}{}
 {}
 {}
 
-#[no_mangle]
-pub extern "C" fn render() -> *mut c_char {{
-    let mut output = String::new();
-{}    {}
-    let c_string = CString::new(output).unwrap();
-    c_string.into_raw()
+// The body goes back as a boxed byte slice: its pointer is returned and its
+// length written to `body_len`. Hand it back to `free_bytes` when done. With
+// a `sink`, that is only what was written after the last `flush()`.
+extern "C" fn render(
+    request: *const u8,
+    request_len: usize,
+    sink: *const RspSink,
+    body_len: *mut usize,
+) -> *mut u8 {{
+    // The library stays loaded between requests; start each one clean.
+    OUTPUT.with(|o| o.borrow_mut().clear());
+    BUFFERS.with(|b| b.borrow_mut().clear());
+    SINK.with(|s| s.set(sink));
+    STATUS_CODE.with(|c| *c.borrow_mut() = 200);
+    REDIRECT.with(|r| *r.borrow_mut() = None);
+    COOKIES.with(|c| c.borrow_mut().clear());
+    HEADERS.with(|h| h.borrow_mut().clear());
+    PANIC.with(|p| *p.borrow_mut() = None);
+    __rsp_install_panic_hook();
+
+    // Unwinding out of an `extern "C"` function aborts the server, so a
+    // panicking page stops here and reports through `get_panic` instead.
+    // `exit()` unwinds to here as well, but leaves no panic behind.
+    RENDERING.with(|r| r.set(true));
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {{
+{}{}
+    }}));
+    RENDERING.with(|r| r.set(false));
+    SINK.with(|s| s.set(std::ptr::null()));
+    // Buffers still open when the page ends are flushed, as in PHP.
+    BUFFERS.with(|b| b.borrow_mut().clear());
+
+    let body = OUTPUT.with(|o| std::mem::take(&mut *o.borrow_mut())).into_boxed_slice();
+    if !body_len.is_null() {{
+        unsafe {{ *body_len = body.len(); }}
+    }}
+    Box::into_raw(body) as *mut u8
 }}
 
-#[no_mangle]
-pub extern "C" fn free_string(s: *mut c_char) {{
+extern "C" fn free_bytes(ptr: *mut u8, len: usize) {{
+    if ptr.is_null() {{ return; }}
+    unsafe {{ drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len))); }}
+}}
+
+extern "C" fn get_panic() -> *mut c_char {{
+    match PANIC.with(|p| p.borrow_mut().take()) {{
+        Some(record) => CString::new(record).unwrap_or_default().into_raw(),
+        None => std::ptr::null_mut(),
+    }}
+}}
+
+extern "C" fn free_string(s: *mut c_char) {{
     if s.is_null() {{ return; }}
     unsafe {{ drop(CString::from_raw(s)); }}
 }}
 
-#[no_mangle]
-pub extern "C" fn get_status_code() -> u16 {{
+extern "C" fn get_status_code() -> u16 {{
     STATUS_CODE.with(|c| *c.borrow())
 }}
 
-#[no_mangle]
-pub extern "C" fn get_redirect() -> *mut c_char {{
+extern "C" fn get_redirect() -> *mut c_char {{
     let redirect = REDIRECT.with(|r| r.borrow().clone());
     match redirect {{
         Some(url) => {{
-            let c_string = CString::new(url).unwrap();
+            let c_string = CString::new(url).unwrap_or_default();
             c_string.into_raw()
         }}
         None => std::ptr::null_mut(),
     }}
 }}
 
-#[no_mangle]
-pub extern "C" fn get_cookies() -> *mut c_char {{
-    let cookies: String = COOKIES.with(|c| {{
-        c.borrow().iter()
-            .map(|(name, value, max_age)| format!("{{}}\t{{}}\t{{}}", name, value, max_age))
-            .collect::<Vec<_>>()
-            .join("\n")
-    }});
-    let c_string = CString::new(cookies).unwrap();
+extern "C" fn get_cookies() -> *mut c_char {{
+    let cookies: String = COOKIES.with(|c| c.borrow().join("\n"));
+    let c_string = CString::new(cookies).unwrap_or_default();
     c_string.into_raw()
 }}
 
-#[no_mangle]
-pub extern "C" fn get_headers() -> *mut c_char {{
+extern "C" fn get_headers() -> *mut c_char {{
     let headers: String = HEADERS.with(|h| {{
         h.borrow().iter()
-            .map(|(name, value)| format!("{{}}:{{}}", name, value))
+            .map(|(name, value)| format!("{{}}\t{{}}", __rsp_field(name), __rsp_field(value)))
             .collect::<Vec<_>>()
             .join("\n")
     }});
-    let c_string = CString::new(headers).unwrap();
+    let c_string = CString::new(headers).unwrap_or_default();
     c_string.into_raw()
 }}
+
+// The only exported symbol: the server checks `abi_version` and
+// `runtime_version` before calling anything else. Must match
+// `PageDescriptor` in the server's loader.
+#[repr(C)]
+pub struct RspPage {{
+    abi_version: u32,
+    runtime_version: *const c_char,
+    render: extern "C" fn(*const u8, usize, *const RspSink, *mut usize) -> *mut u8,
+    free_bytes: extern "C" fn(*mut u8, usize),
+    free_string: extern "C" fn(*mut c_char),
+    get_status_code: extern "C" fn() -> u16,
+    get_redirect: extern "C" fn() -> *mut c_char,
+    get_cookies: extern "C" fn() -> *mut c_char,
+    get_headers: extern "C" fn() -> *mut c_char,
+    get_panic: extern "C" fn() -> *mut c_char,
+}}
+
+unsafe impl Sync for RspPage {{}}
+
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static rsp_page_v1: RspPage = RspPage {{
+    abi_version: {},
+    runtime_version: "{}\0".as_ptr() as *const c_char,
+    render,
+    free_bytes,
+    free_string,
+    get_status_code,
+    get_redirect,
+    get_cookies,
+    get_headers,
+    get_panic,
+}};
 "#,
-            imports, static_code, request_init, render_code
+            COOKIE_HELPERS,
+            PANIC_HELPERS,
+            OUTPUT_HELPERS,
+            // Pages built without rsp-runtime have no `SafeHtml` to special-case.
+            if needs_cargo { SAFE_HTML_OUTPUT } else { "" },
+            IMPORTS_SECTION,
+            STATIC_SECTION,
+            request_init,
+            RENDER_SECTION,
+            ABI_VERSION,
+            rsp_runtime::VERSION
         );
 
-        GeneratedCode {
+        let mut sections = [Some(imports), Some(static_code), Some(render_code)];
+        let mut code = Section::default();
+        for line in skeleton.lines() {
+            let index = [IMPORTS_SECTION, STATIC_SECTION, RENDER_SECTION]
+                .iter()
+                .position(|s| *s == line);
+            match index.and_then(|i| sections[i].take()) {
+                Some(section) => code.append(section),
+                None => code.push_line(line),
+            }
+        }
+        let includes = files[1..].iter().filter_map(|f| f.path.clone()).collect();
+        let (source, source_map) = code.finish(files);
+
+        Ok(GeneratedCode {
             source,
             needs_cargo,
             dependencies,
-        }
+            includes,
+            source_map,
+        })
     }
 }
 
@@ -234,6 +562,209 @@ impl Default for Generator {
     }
 }
 
+/// `cookie(name, value)` builder. The cookie is queued when the builder is
+/// dropped, so `cookie("sid", id).secure();` is a complete statement. Each
+/// cookie crosses to the server as one line of tab-separated fields, which
+/// `parse_cookies` in the loader turns into an `rsp_runtime::Cookie`. It is
+/// part of the prelude so that pages built without rsp-runtime can set
+/// cookies too.
+const COOKIE_HELPERS: &str = r#"
+#[derive(Clone, Copy)]
+enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+use SameSite::{Lax, Strict};
+
+struct CookieBuilder {
+    name: String,
+    value: String,
+    max_age: Option<i64>,
+    expires: Option<std::time::SystemTime>,
+    path: Option<String>,
+    domain: Option<String>,
+    same_site: Option<SameSite>,
+    secure: bool,
+    http_only: bool,
+}
+
+fn cookie(name: &str, value: impl ToString) -> CookieBuilder {
+    CookieBuilder {
+        name: name.to_string(),
+        value: value.to_string(),
+        max_age: None,
+        expires: None,
+        path: Some("/".to_string()),
+        domain: None,
+        same_site: None,
+        secure: false,
+        http_only: true,
+    }
+}
+
+impl CookieBuilder {
+    fn max_age(mut self, seconds: i64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    fn expires(mut self, at: std::time::SystemTime) -> Self {
+        self.expires = Some(at);
+        self
+    }
+
+    fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+}
+
+impl Drop for CookieBuilder {
+    fn drop(&mut self) {
+        let expires = self
+            .expires
+            .and_then(|at| at.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs().to_string());
+        let same_site = self.same_site.map(|s| match s {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        });
+        let fields = [
+            self.name.clone(),
+            self.value.clone(),
+            self.max_age.map(|m| m.to_string()).unwrap_or_default(),
+            expires.unwrap_or_default(),
+            self.path.clone().unwrap_or_default(),
+            self.domain.clone().unwrap_or_default(),
+            same_site.unwrap_or_default().to_string(),
+            if self.secure { "1" } else { "" }.to_string(),
+            if self.http_only { "1" } else { "" }.to_string(),
+        ];
+        let record = fields.iter().map(|f| __rsp_field(f)).collect::<Vec<_>>().join("\t");
+        COOKIES.with(|c| c.borrow_mut().push(record));
+    }
+}
+"#;
+
+/// Records a panic raised during `render` as `message, file, line, column,
+/// in_page` fields, where `in_page` marks panics located in the page's own
+/// source (`file!()`), whose line and column can be mapped to the template.
+/// Panics on other threads still go to the previous hook.
+const PANIC_HELPERS: &str = r#"
+fn __rsp_install_panic_hook() {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if !RENDERING.with(|r| r.get()) {
+                return previous(info);
+            }
+            let payload = info.payload();
+            let message = match payload.downcast_ref::<&str>() {
+                Some(s) => s.to_string(),
+                None => match payload.downcast_ref::<String>() {
+                    Some(s) => s.clone(),
+                    None => "Box<dyn Any>".to_string(),
+                },
+            };
+            let (file, line, column) = info
+                .location()
+                .map(|l| (l.file().to_string(), l.line(), l.column()))
+                .unwrap_or_default();
+            let in_page = if file == file!() { "1" } else { "" };
+            let fields = [message, file, line.to_string(), column.to_string(), in_page.to_string()];
+            let record = fields.iter().map(|f| __rsp_field(f)).collect::<Vec<_>>().join("\t");
+            PANIC.with(|p| *p.borrow_mut() = Some(record));
+        }));
+    });
+}
+"#;
+
+/// Writes `<%= %>` output HTML-escaped. Method resolution picks the
+/// `SAFE_HTML_OUTPUT` impl over this one when the value is a `SafeHtml`,
+/// because it matches without the extra autoref.
+const OUTPUT_HELPERS: &str = r#"struct __RspOutput<'a, T: ?Sized>(&'a T);
+
+trait __RspEscape {
+    fn __rsp_write(&self);
+}
+
+impl<T: std::fmt::Display + ?Sized> __RspEscape for &__RspOutput<'_, T> {
+    fn __rsp_write(&self) {
+        let text = self.0.to_string();
+        let mut output = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '&' => output.push_str("&amp;"),
+                '<' => output.push_str("&lt;"),
+                '>' => output.push_str("&gt;"),
+                '"' => output.push_str("&quot;"),
+                '\'' => output.push_str("&#39;"),
+                c => output.push(c),
+            }
+        }
+        __rsp_echo(&output);
+    }
+}
+"#;
+
+const SAFE_HTML_OUTPUT: &str = r#"
+trait __RspRaw {
+    fn __rsp_write(&self);
+}
+
+impl __RspRaw for __RspOutput<'_, rsp_runtime::SafeHtml> {
+    fn __rsp_write(&self) {
+        __rsp_echo(self.0.as_str());
+    }
+}
+"#;
+
+/// Sources (indices into `SourceMap::files`) that turned escaping off with
+/// `<%@ autoescape off %>`. The directive applies to the file it is
+/// written in, not to the files it includes or extends.
+fn autoescape_off(tokens: &[Token]) -> Result<Vec<usize>, GenerateError> {
+    let mut sources = Vec::new();
+    for token in tokens {
+        let Token::Directive(directive, span) = token else {
+            continue;
+        };
+        let mut words = directive.split_whitespace();
+        if words.next() != Some("autoescape") {
+            continue;
+        }
+        match (words.next(), words.next()) {
+            (Some("off"), None) => sources.push(span.source),
+            (Some("on"), None) => {}
+            _ => return Err(GenerateError::InvalidDirective(directive.clone())),
+        }
+    }
+    Ok(sources)
+}
+
 fn escape_string(s: &str) -> String {
     s.replace('\\', "\\\\")
         .replace('"', "\\\"")
@@ -241,3 +772,697 @@ fn escape_string(s: &str) -> String {
         .replace('\r', "\\r")
         .replace('\t', "\\t")
 }
+
+/// Expands includes and applies `<%@ extends %>` for one template. Block
+/// markers are kept so that a template extending this one can still
+/// override them; `strip_block_markers` removes them once at the end.
+fn resolve_tokens(
+    tokens: &[Token],
+    current: Option<&Path>,
+    docroot: Option<&Path>,
+    stack: &mut Vec<PathBuf>,
+    files: &mut Vec<SourceFile>,
+) -> Result<Vec<Token>, GenerateError> {
+    let tokens = expand_includes(tokens, current, docroot, stack, files)?;
+    apply_layout(tokens, current, docroot, stack, files)
+}
+
+/// Reads, parses and resolves the template at `path`. `stack` holds the
+/// canonical paths of the files currently being resolved so cycles can be
+/// reported.
+fn load_template(
+    path: &Path,
+    docroot: Option<&Path>,
+    stack: &mut Vec<PathBuf>,
+    files: &mut Vec<SourceFile>,
+) -> Result<Vec<Token>, GenerateError> {
+    let canonical = path.canonicalize()?;
+
+    if stack.contains(&canonical) {
+        let chain: Vec<String> = stack
+            .iter()
+            .chain(std::iter::once(&canonical))
+            .map(|p| p.display().to_string())
+            .collect();
+        return Err(GenerateError::Cycle(chain.join(" -> ")));
+    }
+
+    // Taken before reading, so a write in between shows up as a change.
+    let stamp = FileStamp::of(&canonical)?;
+    let content = std::fs::read_to_string(&canonical)?;
+    let mut parsed = Parser::new()
+        .parse(&content)
+        .map_err(|error| GenerateError::Parse {
+            path: canonical.clone(),
+            error: error.with_file(&display_name(&canonical, docroot)),
+        })?;
+
+    let source = match files
+        .iter()
+        .position(|f| f.path.as_ref() == Some(&canonical))
+    {
+        Some(index) => index,
+        None => {
+            files.push(SourceFile {
+                name: display_name(&canonical, docroot),
+                path: Some(canonical.clone()),
+                content,
+                stamp: Some(stamp),
+            });
+            files.len() - 1
+        }
+    };
+    for token in &mut parsed.tokens {
+        token.span_mut().source = source;
+    }
+
+    stack.push(canonical.clone());
+    let tokens = resolve_tokens(&parsed.tokens, Some(&canonical), docroot, stack, files);
+    stack.pop();
+
+    tokens
+}
+
+/// Name of a template file in diagnostics: relative to the docroot if it is
+/// inside it, as given otherwise.
+pub(crate) fn display_name(path: &Path, docroot: Option<&Path>) -> String {
+    docroot
+        .and_then(|root| root.canonicalize().ok())
+        .and_then(|root| {
+            let path = path.canonicalize().ok()?;
+            path.strip_prefix(root).ok().map(Path::to_path_buf)
+        })
+        .unwrap_or_else(|| path.to_path_buf())
+        .display()
+        .to_string()
+}
+
+/// Replaces every `<%@ include %>` directive with the tokens of the included
+/// template.
+fn expand_includes(
+    tokens: &[Token],
+    current: Option<&Path>,
+    docroot: Option<&Path>,
+    stack: &mut Vec<PathBuf>,
+    files: &mut Vec<SourceFile>,
+) -> Result<Vec<Token>, GenerateError> {
+    let mut expanded = Vec::with_capacity(tokens.len());
+
+    for token in tokens {
+        let target = match token {
+            Token::Directive(directive, _) => directive_path(directive, "include")?,
+            _ => None,
+        };
+        let Some(target) = target else {
+            expanded.push(token.clone());
+            continue;
+        };
+
+        let path = resolve_template_path(&target, current, docroot)
+            .ok_or_else(|| GenerateError::IncludeNotFound(target.clone()))?;
+        expanded.extend(load_template(&path, docroot, stack, files)?);
+    }
+
+    Ok(expanded)
+}
+
+/// If the template extends a layout, returns the layout's tokens with this
+/// template's blocks substituted in. Code, directives and declarations
+/// outside of any block are kept and run before the layout; text and
+/// expressions outside of blocks are dropped.
+fn apply_layout(
+    tokens: Vec<Token>,
+    current: Option<&Path>,
+    docroot: Option<&Path>,
+    stack: &mut Vec<PathBuf>,
+    files: &mut Vec<SourceFile>,
+) -> Result<Vec<Token>, GenerateError> {
+    let mut layout = None;
+    for token in &tokens {
+        if let Token::Directive(directive, _) = token {
+            if let Some(target) = directive_path(directive, "extends")? {
+                if layout.is_some() {
+                    return Err(GenerateError::MultipleExtends);
+                }
+                layout = Some(target);
+            }
+        }
+    }
+    let Some(layout) = layout else {
+        return Ok(tokens);
+    };
+
+    let (prelude, overrides) = split_blocks(&tokens)?;
+
+    let path = resolve_template_path(&layout, current, docroot)
+        .ok_or_else(|| GenerateError::LayoutNotFound(layout.clone()))?;
+    let layout_tokens = load_template(&path, docroot, stack, files)?;
+
+    let defined = block_names(&layout_tokens);
+    for (name, _) in &overrides {
+        if !defined.contains(name) {
+            return Err(GenerateError::UnknownBlock {
+                name: name.clone(),
+                layout,
+            });
+        }
+    }
+
+    let mut resolved = prelude;
+    let mut iter = layout_tokens.into_iter();
+    while let Some(token) = iter.next() {
+        let name = match &token {
+            Token::Directive(directive, _) => block_name(directive),
+            _ => None,
+        };
+        let Some(body) = name.and_then(|name| {
+            overrides
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, body)| body.clone())
+        }) else {
+            resolved.push(token);
+            continue;
+        };
+
+        // Skip the layout's default content, keeping it for `<%@ parent %>`.
+        let mut default = Vec::new();
+        let mut depth = 0;
+        for inner in iter.by_ref() {
+            match &inner {
+                Token::Directive(d, _) if block_name(d).is_some() => depth += 1,
+                Token::Directive(d, _) if is_endblock(d) => {
+                    if depth == 0 {
+                        break;
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+            default.push(inner);
+        }
+
+        let span = token.span();
+        resolved.push(token);
+        for inner in body {
+            match &inner {
+                Token::Directive(d, _) if d.trim() == "parent" => {
+                    resolved.extend(default.iter().cloned())
+                }
+                _ => resolved.push(inner),
+            }
+        }
+        resolved.push(Token::Directive("endblock".to_string(), span));
+    }
+
+    Ok(resolved)
+}
+
+/// Splits a child template into the tokens that live outside any block and
+/// the content of every top-level block it defines.
+#[allow(clippy::type_complexity)]
+fn split_blocks(
+    tokens: &[Token],
+) -> Result<(Vec<Token>, Vec<(String, Vec<Token>)>), GenerateError> {
+    let mut prelude = Vec::new();
+    let mut blocks: Vec<(String, Vec<Token>)> = Vec::new();
+    let mut open: Vec<String> = Vec::new();
+
+    for token in tokens {
+        if let Token::Directive(directive, _) = token {
+            if let Some(name) = block_name(directive) {
+                if open.is_empty() {
+                    if blocks.iter().any(|(n, _)| *n == name) {
+                        return Err(GenerateError::DuplicateBlock(name));
+                    }
+                    blocks.push((name.clone(), Vec::new()));
+                    open.push(name);
+                    continue;
+                }
+                open.push(name);
+            } else if is_endblock(directive) {
+                if open.pop().is_none() {
+                    return Err(GenerateError::UnexpectedEndblock);
+                }
+                if open.is_empty() {
+                    continue;
+                }
+            }
+        }
+
+        match (open.is_empty(), token) {
+            (false, _) => blocks.last_mut().unwrap().1.push(token.clone()),
+            (true, Token::Text(..) | Token::Expression(..) | Token::RawExpression(..)) => {}
+            (true, Token::Directive(d, _)) if directive_path(d, "extends")?.is_some() => {}
+            (true, _) => prelude.push(token.clone()),
+        }
+    }
+
+    if let Some(name) = open.pop() {
+        return Err(GenerateError::UnclosedBlock(name));
+    }
+
+    Ok((prelude, blocks))
+}
+
+fn block_names(tokens: &[Token]) -> Vec<String> {
+    tokens
+        .iter()
+        .filter_map(|t| match t {
+            Token::Directive(d, _) => block_name(d),
+            _ => None,
+        })
+        .collect()
+}
+
+fn block_name(directive: &str) -> Option<String> {
+    let name = directive.trim().strip_prefix("block")?;
+    if !name.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(name.trim().to_string())
+}
+
+fn is_endblock(directive: &str) -> bool {
+    let directive = directive.trim();
+    directive == "endblock" || directive.starts_with("endblock ")
+}
+
+/// Removes the block markers left by `apply_layout`, checking that every
+/// block is closed.
+fn strip_block_markers(tokens: Vec<Token>) -> Result<Vec<Token>, GenerateError> {
+    let mut open = Vec::new();
+    let mut stripped = Vec::with_capacity(tokens.len());
+
+    for token in tokens {
+        if let Token::Directive(directive, _) = &token {
+            if let Some(name) = block_name(directive) {
+                open.push(name);
+                continue;
+            }
+            if is_endblock(directive) {
+                if open.pop().is_none() {
+                    return Err(GenerateError::UnexpectedEndblock);
+                }
+                continue;
+            }
+            if directive.trim() == "parent" {
+                continue;
+            }
+        }
+        stripped.push(token);
+    }
+
+    match open.pop() {
+        Some(name) => Err(GenerateError::UnclosedBlock(name)),
+        None => Ok(stripped),
+    }
+}
+
+/// Returns the quoted path of a `<keyword> "path"` directive, or `None` for
+/// any other directive.
+fn directive_path(directive: &str, keyword: &str) -> Result<Option<String>, GenerateError> {
+    let directive = directive.trim();
+    let rest = match directive.strip_prefix(keyword) {
+        Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => rest.trim(),
+        _ => return Ok(None),
+    };
+
+    let target = rest
+        .strip_prefix('"')
+        .and_then(|r| r.strip_suffix('"'))
+        .or_else(|| rest.strip_prefix('\'').and_then(|r| r.strip_suffix('\'')))
+        .unwrap_or(rest);
+
+    if target.is_empty() || target.contains(char::is_whitespace) {
+        return Err(GenerateError::InvalidDirective(directive.to_string()));
+    }
+
+    Ok(Some(target.to_string()))
+}
+
+/// Paths starting with `/` are relative to the docroot. Anything else is
+/// looked up next to the current file first, then under the docroot.
+fn resolve_template_path(
+    target: &str,
+    current: Option<&Path>,
+    docroot: Option<&Path>,
+) -> Option<PathBuf> {
+    let mut candidates = Vec::new();
+
+    if let Some(rooted) = target.strip_prefix('/') {
+        candidates.push(docroot.unwrap_or(Path::new(".")).join(rooted));
+    } else {
+        if let Some(dir) = current.and_then(Path::parent) {
+            candidates.push(dir.join(target));
+        }
+        candidates.push(docroot.unwrap_or(Path::new(".")).join(target));
+    }
+
+    candidates.into_iter().find(|p| p.is_file())
+}
+
+fn push_dependency(dependencies: &mut Vec<String>, dep: String) {
+    if !dependencies.contains(&dep) {
+        dependencies.push(dep);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::loader::{Chunk, Loader};
+    use rsp_runtime::RequestData;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rsp-gen-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn generate(dir: &Path, page: &str) -> Result<GeneratedCode, GenerateError> {
+        let path = dir.join(page);
+        let content = std::fs::read_to_string(&path).unwrap();
+        let parsed = Parser::new().parse(&content).unwrap();
+        let options = GenerateOptions {
+            path: Some(path),
+            docroot: Some(dir.to_path_buf()),
+        };
+        Generator::new().generate_with_options(&parsed, options)
+    }
+
+    #[test]
+    fn test_include_inlines_and_merges_directives() {
+        let dir = temp_dir("include");
+        std::fs::create_dir_all(dir.join("partials")).unwrap();
+        std::fs::write(
+            dir.join("partials/header.rsp"),
+            "<%@ once_cell %><%@ use std::sync::Mutex %><%! static N: i32 = 1; %><h1>Header</h1>",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("page.rsp"),
+            "<%@ once_cell %><%@ include \"partials/header.rsp\" %><%@ include \"/partials/header.rsp\" %><p>Body</p>",
+        )
+        .unwrap();
+
+        let generated = generate(&dir, "page.rsp").unwrap();
+        assert_eq!(generated.includes.len(), 1);
+        assert_eq!(generated.dependencies, vec!["once_cell = \"1\""]);
+        assert_eq!(generated.source.matches("static N: i32 = 1;").count(), 1);
+        assert_eq!(generated.source.matches("use std::sync::Mutex;").count(), 1);
+        assert_eq!(generated.source.matches("<h1>Header</h1>").count(), 2);
+        assert!(generated.source.contains("<p>Body</p>"));
+        let header = &generated.source_map.files[1];
+        assert_eq!(
+            header.stamp,
+            Some(FileStamp::of(&dir.join("partials/header.rsp")).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_include_cycle_is_rejected() {
+        let dir = temp_dir("cycle");
+        std::fs::write(dir.join("a.rsp"), "<%@ include \"b.rsp\" %>").unwrap();
+        std::fs::write(dir.join("b.rsp"), "<%@ include \"a.rsp\" %>").unwrap();
+
+        assert!(matches!(
+            generate(&dir, "a.rsp"),
+            Err(GenerateError::Cycle(_))
+        ));
+    }
+
+    #[test]
+    fn test_include_missing_file() {
+        let dir = temp_dir("missing");
+        std::fs::write(dir.join("page.rsp"), "<%@ include \"nope.rsp\" %>").unwrap();
+
+        assert!(matches!(
+            generate(&dir, "page.rsp"),
+            Err(GenerateError::IncludeNotFound(_))
+        ));
+    }
+
+    fn render_text(generated: &GeneratedCode) -> String {
+        generated
+            .source
+            .lines()
+            .filter_map(|l| l.trim().strip_prefix("__rsp_echo(\""))
+            .map(|l| l.trim_end_matches("\");"))
+            .collect()
+    }
+
+    #[test]
+    fn test_extends_overrides_blocks() {
+        let dir = temp_dir("extends");
+        std::fs::write(
+            dir.join("layout.rsp"),
+            "<title><%@ block title %>Default<%@ endblock %></title><%@ block body %><%@ endblock %><footer/>",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("page.rsp"),
+            "<%@ extends \"layout.rsp\" %><% let n = 1; %>ignored<%@ block body %><p>Hi</p><%@ endblock %>",
+        )
+        .unwrap();
+
+        let generated = generate(&dir, "page.rsp").unwrap();
+        assert_eq!(
+            render_text(&generated),
+            "<title>Default</title><p>Hi</p><footer/>"
+        );
+        assert!(generated.source.contains("let n = 1;"));
+        assert_eq!(generated.includes.len(), 1);
+    }
+
+    #[test]
+    fn test_extends_multi_level_with_parent() {
+        let dir = temp_dir("multilevel");
+        std::fs::write(
+            dir.join("base.rsp"),
+            "[<%@ block head %>base-head<%@ endblock %>|<%@ block body %><%@ endblock %>]",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("section.rsp"),
+            "<%@ extends \"base.rsp\" %><%@ block head %><%@ parent %>+section<%@ endblock %>",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("page.rsp"),
+            "<%@ extends \"section.rsp\" %><%@ block head %><%@ parent %>+page<%@ endblock %><%@ block body %>body<%@ endblock %>",
+        )
+        .unwrap();
+
+        let generated = generate(&dir, "page.rsp").unwrap();
+        assert_eq!(render_text(&generated), "[base-head+section+page|body]");
+        assert_eq!(generated.includes.len(), 2);
+    }
+
+    #[test]
+    fn test_extends_unknown_block() {
+        let dir = temp_dir("unknown-block");
+        std::fs::write(dir.join("layout.rsp"), "<%@ block body %><%@ endblock %>").unwrap();
+        std::fs::write(
+            dir.join("page.rsp"),
+            "<%@ extends \"layout.rsp\" %><%@ block sidebar %>x<%@ endblock %>",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            generate(&dir, "page.rsp"),
+            Err(GenerateError::UnknownBlock { name, .. }) if name == "sidebar"
+        ));
+    }
+
+    #[test]
+    fn test_unclosed_block() {
+        let dir = temp_dir("unclosed-block");
+        std::fs::write(dir.join("page.rsp"), "<%@ block body %>x").unwrap();
+
+        assert!(matches!(
+            generate(&dir, "page.rsp"),
+            Err(GenerateError::UnclosedBlock(name)) if name == "body"
+        ));
+    }
+
+    #[test]
+    fn test_autoescape() {
+        let dir = temp_dir("autoescape");
+        std::fs::write(dir.join("legacy.rsp"), "<%@ autoescape off %><%= legacy %>").unwrap();
+        std::fs::write(
+            dir.join("page.rsp"),
+            "<%= name %><%== html %><%@ include \"legacy.rsp\" %>",
+        )
+        .unwrap();
+
+        let source = generate(&dir, "page.rsp").unwrap().source;
+        assert!(source.contains("(&__RspOutput(&(name))).__rsp_write();"));
+        assert!(source.contains("__rsp_echo(&format!(\"{}\", html));"));
+        assert!(source.contains("__rsp_echo(&format!(\"{}\", legacy));"));
+        assert!(!source.contains("rsp_runtime::SafeHtml"));
+
+        std::fs::write(dir.join("page.rsp"), "<%= SafeHtml::from(\"<b>\") %>").unwrap();
+        let source = generate(&dir, "page.rsp").unwrap().source;
+        assert!(source.contains("impl __RspRaw for __RspOutput<'_, rsp_runtime::SafeHtml>"));
+
+        std::fs::write(dir.join("page.rsp"), "<%@ autoescape maybe %>").unwrap();
+        assert!(matches!(
+            generate(&dir, "page.rsp"),
+            Err(GenerateError::InvalidDirective(_))
+        ));
+    }
+
+    #[test]
+    fn test_error_object() {
+        let dir = temp_dir("error-object");
+
+        // Error pages reach the error through `req`, like everything else
+        // about the request.
+        std::fs::write(
+            dir.join("404.rsp"),
+            "<% if let Some(error) = &req.error { %><%= error.status %><% } %>",
+        )
+        .unwrap();
+        let generated = generate(&dir, "404.rsp").unwrap();
+        assert!(generated.source.contains("let req = rsp_runtime::req();"));
+        assert!(generated.needs_cargo);
+
+        // A page's own `error` is left alone.
+        std::fs::write(
+            dir.join("page.rsp"),
+            "<% let error = \"x\"; %><%= match \"1\".parse::<u8>() { Ok(n) => n.to_string(), Err(error) => error.to_string() } %><%= error.len() %>",
+        )
+        .unwrap();
+        let generated = generate(&dir, "page.rsp").unwrap();
+        assert!(!generated.source.contains("req.error"));
+        assert!(!generated.needs_cargo);
+    }
+
+    /// Compiles and runs a page that doesn't need the runtime, streaming
+    /// it. Returns the body chunks in the order the page sent them.
+    fn run_streaming(name: &str, template: &str) -> Vec<Vec<u8>> {
+        let dir = temp_dir(name);
+        let parsed = Parser::new().parse(template).unwrap();
+        let generated = Generator::new()
+            .generate_with_options(&parsed, GenerateOptions::default())
+            .unwrap();
+        assert!(!generated.needs_cargo);
+        let lib_path = Compiler::new(dir).compile(&generated.source, name).unwrap();
+
+        let mut chunks = Vec::new();
+        Loader::new()
+            .load(&lib_path)
+            .unwrap()
+            .render_streaming(&RequestData::default(), &mut |chunk| {
+                if let Chunk::Body(bytes) = chunk {
+                    chunks.push(bytes.to_vec());
+                }
+            })
+            .unwrap();
+        chunks
+    }
+
+    fn texts(chunks: Vec<Vec<u8>>) -> Vec<String> {
+        chunks
+            .into_iter()
+            .map(|c| String::from_utf8(c).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_nested_output_buffers() {
+        let chunks = run_streaming(
+            "ob-nested",
+            "<%= ob_end_flush() %><%= ob_get_clean().len() %>a\
+             <% ob_start(); %>b<% ob_start(); %>c<% let inner = ob_get_clean(); %>d\
+             <% let level = ob_get_level(); let outer = ob_get_clean(); %>\
+             [<%== outer %>|<%== inner %>|<%= level %>]\
+             <% ob_start(); %>e<% ob_start(); %>f<% ob_end_flush(); %>g",
+        );
+        // Buffers still open at the end are sent with the rest.
+        assert_eq!(texts(chunks), ["false0a[bd|c|1]efg"]);
+    }
+
+    #[test]
+    fn test_flush_inside_output_buffers() {
+        let chunks = run_streaming(
+            "ob-flush",
+            "a<% flush(); %>b<% ob_start(); %>c<% ob_start(); %>d\
+             <% flush(); let inner = ob_get_clean(); let outer = ob_get_clean(); %>\
+             [<%== outer %>|<%== inner %>]<% flush(); %>e",
+        );
+        // The flush inside the buffers only sends what came before them, and
+        // the buffers still capture the right ranges afterwards.
+        assert_eq!(texts(chunks), ["a", "b", "[c|d]", "e"]);
+    }
+
+    #[test]
+    fn test_halt_drops_output_buffers() {
+        let chunks = run_streaming(
+            "ob-halt",
+            "a<% flush(); %>b<% ob_start(); %>c<% ob_start(); %>d<% halt(false); %>e",
+        );
+        assert_eq!(texts(chunks), ["a"]);
+
+        let chunks = run_streaming(
+            "ob-exit",
+            "a<% ob_start(); %>b<% ob_start(); %>c<% exit(); %>d",
+        );
+        assert_eq!(texts(chunks), ["abc"]);
+    }
+
+    #[test]
+    fn test_output_buffer_bytes() {
+        let chunks = run_streaming(
+            "ob-bytes",
+            "<% ob_start(); write_bytes(&[0xff, 0]); let raw = ob_get_clean_bytes(); \
+             ob_start(); write_bytes(&[0xff]); let text = ob_get_clean(); \
+             write_bytes(&raw); %><%= text %>",
+        );
+        assert_eq!(chunks, [vec![0xff, 0, 0xef, 0xbf, 0xbd]]);
+    }
+
+    #[test]
+    fn test_response_helpers_without_runtime() {
+        let dir = temp_dir("response-helpers");
+        let parsed = Parser::new()
+            .parse(
+                "<% content_type(\"text/plain\"); set_header(\"X-A\", \"1\"); \
+                 remove_header(\"X-A\"); header_url(\"/login\"); \
+                 cookie(\"sid\", 1).same_site(Lax); CleanCookie(\"old\"); %>",
+            )
+            .unwrap();
+        let generated = Generator::new()
+            .generate_with_options(&parsed, GenerateOptions::default())
+            .unwrap();
+        // Everything used here lives in the prelude; building against
+        // rsp-runtime would only slow the page down.
+        assert!(!generated.needs_cargo);
+
+        let lib_path = Compiler::new(dir)
+            .compile(&generated.source, "response-helpers")
+            .unwrap();
+        let (_, status, redirect, cookies, headers) = Loader::new()
+            .load(&lib_path)
+            .unwrap()
+            .render(&RequestData::default())
+            .unwrap();
+        assert_eq!(status, 302);
+        assert_eq!(redirect.as_deref(), Some("/login"));
+        assert_eq!(
+            headers,
+            [("Content-Type".to_string(), "text/plain".to_string())]
+        );
+        let cookies: Vec<String> = cookies.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            cookies,
+            [
+                "sid=1; Path=/; SameSite=Lax; HttpOnly",
+                "old=; Max-Age=0; Path=/; HttpOnly"
+            ]
+        );
+    }
+}
@@ -1,11 +1,20 @@
+pub mod access;
 pub mod compiler;
 pub mod engine;
+pub mod error_page;
 pub mod generator;
 pub mod loader;
 pub mod parser;
+pub mod router;
+pub mod source_map;
+pub mod watch;
 
-pub use compiler::{CompileError, CompileOptions, Compiler};
-pub use engine::{RenderResult, RspEngine, RspError};
-pub use generator::{GeneratedCode, Generator};
-pub use loader::{LoadError, Loader};
-pub use parser::{ParseError, ParsedTemplate, Parser, Token};
+pub use access::{AccessError, AccessRules};
+pub use compiler::{CompileError, CompileOptions, Compiler, Diagnostic, GeneratedSpan};
+pub use engine::{PanicReport, RenderResult, RspEngine, RspError};
+pub use generator::{GenerateError, GenerateOptions, GeneratedCode, Generator};
+pub use loader::{LoadError, Loader, PagePanic};
+pub use parser::{ParseError, ParsedTemplate, Parser, Span, Token};
+pub use router::{find_page, PageLookup, RouteError, RouteMatch, Routes};
+pub use source_map::{FileStamp, SourceFile, SourceMap, TemplateLocation};
+pub use watch::Watcher;
@@ -1,17 +1,237 @@
-use libloading::{Library, Symbol};
+use libloading::Library;
+use rsp_runtime::{Cookie, RequestData, SameSite};
 use std::collections::HashMap;
 use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::panic::AssertUnwindSafe;
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
+/// Body, status code, redirect, cookies and headers produced by one render.
+pub type PageResponse = (
+    Vec<u8>,
+    u16,
+    Option<String>,
+    Vec<Cookie>,
+    Vec<(String, String)>,
+);
+
+/// Loads compiled pages and runs them. Libraries are shared behind `Arc`s,
+/// so any number of threads can render at once. The loader keeps each one
+/// until its path is passed to `unload`, or its file changes; after that it
+/// is unloaded as soon as nothing else holds it and the renders still using
+/// it have finished.
 pub struct Loader {
-    libraries: HashMap<PathBuf, LoadedLib>,
+    libraries: RwLock<HashMap<PathBuf, Arc<LoadedLib>>>,
+}
+
+/// Version of the interface between the server and compiled pages. Bump it
+/// whenever `PageDescriptor` or the generated prelude changes in a way older
+/// pages can't satisfy; it is also mixed into the page hash, so pages get
+/// rebuilt into fresh cache entries.
+pub const ABI_VERSION: u32 = 3;
+
+type StringFn = extern "C" fn() -> *mut c_char;
+
+/// Passed to `render` to stream the page; mirrors `RspSink` in the
+/// generated code. `ctx` points at a `Stream`.
+#[repr(C)]
+struct Sink {
+    ctx: *mut c_void,
+    write: extern "C" fn(*mut c_void, *const u8, usize),
+}
+
+/// Output of a streaming render, in order: the head once, then the body in
+/// as many pieces as the page flushed.
+pub enum Chunk<'a> {
+    /// Status code, redirect, cookies and headers as they were at the first
+    /// flush. The body part of the response is always empty.
+    Head(PageResponse),
+    Body(&'a [u8]),
+}
+
+/// State behind `Sink::ctx` while a page streams.
+struct Stream<'a> {
+    loaded: &'a LoadedLib,
+    sink: &'a mut dyn FnMut(Chunk<'_>),
+    started: bool,
+}
+
+impl Stream<'_> {
+    fn write(&mut self, bytes: &[u8]) {
+        if !self.started {
+            self.started = true;
+            let head = unsafe { self.loaded.head() };
+            (self.sink)(Chunk::Head(head));
+        }
+        if !bytes.is_empty() {
+            (self.sink)(Chunk::Body(bytes));
+        }
+    }
+}
+
+extern "C" fn stream_write(ctx: *mut c_void, ptr: *const u8, len: usize) {
+    let stream = unsafe { &mut *(ctx as *mut Stream) };
+    let bytes = if ptr.is_null() {
+        &[][..]
+    } else {
+        unsafe { std::slice::from_raw_parts(ptr, len) }
+    };
+    // This runs inside the page's `render`; a panic must not unwind into it.
+    let _ = std::panic::catch_unwind(AssertUnwindSafe(|| stream.write(bytes)));
 }
 
-struct LoadedLib {
-    library: Library,
+/// The `rsp_page_v1` static every page exports; mirrors `RspPage` in the
+/// generated code.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PageDescriptor {
+    abi_version: u32,
+    runtime_version: *const c_char,
+    render: extern "C" fn(*const u8, usize, *const Sink, *mut usize) -> *mut u8,
+    free_bytes: extern "C" fn(*mut u8, usize),
+    free_string: extern "C" fn(*mut c_char),
+    get_status_code: extern "C" fn() -> u16,
+    get_redirect: StringFn,
+    get_cookies: StringFn,
+    get_headers: StringFn,
+    get_panic: StringFn,
+}
+
+/// A loaded page and its descriptor. The function pointers in `page` are
+/// only valid while `_library` is alive.
+pub struct LoadedLib {
+    page: PageDescriptor,
     modified: SystemTime,
+    _library: Library,
+}
+
+// The descriptor only holds function pointers and a pointer to a static
+// string, all of which live as long as the library.
+unsafe impl Send for LoadedLib {}
+unsafe impl Sync for LoadedLib {}
+
+impl LoadedLib {
+    /// See `Loader::render_with_response`.
+    pub fn render(&self, request: &RequestData) -> Result<PageResponse, LoadError> {
+        let request = serde_json::to_vec(request)?;
+
+        unsafe {
+            let mut len = 0;
+            let body =
+                (self.page.render)(request.as_ptr(), request.len(), std::ptr::null(), &mut len);
+            let content = self.take_bytes(body, len);
+            if let Some(record) = self.take_string((self.page.get_panic)()) {
+                return Err(LoadError::Panic(PagePanic::parse(&record)));
+            }
+            let (_, status_code, redirect, cookies, headers) = self.head();
+            Ok((content, status_code, redirect, cookies, headers))
+        }
+    }
+
+    /// See `Loader::render_streaming`.
+    pub fn render_streaming(
+        &self,
+        request: &RequestData,
+        sink: &mut dyn FnMut(Chunk<'_>),
+    ) -> Result<(), LoadError> {
+        let request = serde_json::to_vec(request)?;
+
+        let mut stream = Stream {
+            loaded: self,
+            sink,
+            started: false,
+        };
+        unsafe {
+            let handle = Sink {
+                ctx: &mut stream as *mut Stream as *mut c_void,
+                write: stream_write,
+            };
+            let mut len = 0;
+            let body = (self.page.render)(request.as_ptr(), request.len(), &handle, &mut len);
+            let content = self.take_bytes(body, len);
+            if let Some(record) = self.take_string((self.page.get_panic)()) {
+                return Err(LoadError::Panic(PagePanic::parse(&record)));
+            }
+            stream.write(&content);
+        }
+        Ok(())
+    }
+
+    fn open(lib_path: &Path, modified: SystemTime) -> Result<Self, LoadError> {
+        let library = unsafe { Library::new(lib_path) }?;
+        let page = unsafe {
+            let descriptor = library
+                .get::<*const PageDescriptor>(b"rsp_page_v1")
+                .map_err(|_| LoadError::Incompatible("no rsp_page_v1 descriptor".to_string()))?;
+            let descriptor: *const PageDescriptor = *descriptor;
+            // Read the version on its own first; the rest of the layout is
+            // only known to match once it agrees.
+            let abi_version = std::ptr::addr_of!((*descriptor).abi_version).read();
+            if abi_version != ABI_VERSION {
+                return Err(LoadError::Incompatible(format!(
+                    "page ABI version {}, expected {}",
+                    abi_version, ABI_VERSION
+                )));
+            }
+            *descriptor
+        };
+        let runtime_version = unsafe { CStr::from_ptr(page.runtime_version) }.to_string_lossy();
+        if runtime_version != rsp_runtime::VERSION {
+            return Err(LoadError::Incompatible(format!(
+                "page built for rsp-runtime {}, server has {}",
+                runtime_version,
+                rsp_runtime::VERSION
+            )));
+        }
+
+        Ok(LoadedLib {
+            page,
+            modified,
+            _library: library,
+        })
+    }
+
+    /// Takes ownership of a string returned by the page.
+    unsafe fn take_string(&self, ptr: *mut c_char) -> Option<String> {
+        if ptr.is_null() {
+            return None;
+        }
+        let s = CStr::from_ptr(ptr).to_string_lossy().into_owned();
+        (self.page.free_string)(ptr);
+        Some(s)
+    }
+
+    /// Takes ownership of a byte buffer returned by the page.
+    unsafe fn take_bytes(&self, ptr: *mut u8, len: usize) -> Vec<u8> {
+        if ptr.is_null() {
+            return Vec::new();
+        }
+        let bytes = std::slice::from_raw_parts(ptr, len).to_vec();
+        (self.page.free_bytes)(ptr, len);
+        bytes
+    }
+
+    /// Status code, redirect, cookies and headers set by the page so far,
+    /// with an empty body.
+    unsafe fn head(&self) -> PageResponse {
+        let page = &self.page;
+        let status_code = (page.get_status_code)();
+        let redirect = self
+            .take_string((page.get_redirect)())
+            .filter(|s| !s.is_empty());
+        let cookies = self
+            .take_string((page.get_cookies)())
+            .map(|s| parse_cookies(&s))
+            .unwrap_or_default();
+        let headers = self
+            .take_string((page.get_headers)())
+            .map(|s| parse_headers(&s))
+            .unwrap_or_default();
+        (Vec::new(), status_code, redirect, cookies, headers)
+    }
 }
 
 #[derive(Error, Debug)]
@@ -22,167 +242,262 @@ pub enum LoadError {
     Symbol(#[from] std::ffi::NulError),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("Failed to encode request: {0}")]
+    Request(#[from] serde_json::Error),
+    /// The library was built by a different version of rsp and has to be
+    /// rebuilt.
+    #[error("Incompatible page library: {0}")]
+    Incompatible(String),
+    #[error("Page panicked at {}: {}", .0.origin, .0.message)]
+    Panic(PagePanic),
+}
+
+/// A panic caught inside a page's `render`.
+#[derive(Debug, Clone)]
+pub struct PagePanic {
+    pub message: String,
+    /// `file:line:column` where the panic was raised.
+    pub origin: String,
+    /// Line and column in the generated source, if the panic was raised by
+    /// the page's own code rather than a library it called.
+    pub generated: Option<(usize, usize)>,
+}
+
+impl PagePanic {
+    fn parse(record: &str) -> Self {
+        let fields = records(record).next().unwrap_or_default();
+        let field = |i: usize| fields.get(i).map(String::as_str).unwrap_or("");
+        let (line, column) = (field(2), field(3));
+        PagePanic {
+            message: field(0).to_string(),
+            origin: format!("{}:{}:{}", field(1), line, column),
+            generated: match (field(4).is_empty(), line.parse(), column.parse()) {
+                (false, Ok(line), Ok(column)) => Some((line, column)),
+                _ => None,
+            },
+        }
+    }
 }
 
 impl Loader {
     pub fn new() -> Self {
         Loader {
-            libraries: HashMap::new(),
+            libraries: RwLock::new(HashMap::new()),
         }
     }
 
-    pub fn render(&mut self, lib_path: &Path) -> Result<String, LoadError> {
-        let (content, _, _, _, _) = self.render_with_response(lib_path)?;
+    pub fn render(&self, lib_path: &Path) -> Result<Vec<u8>, LoadError> {
+        let (content, _, _, _, _) = self.render_with_response(lib_path, &RequestData::default())?;
         Ok(content)
     }
 
+    /// Runs a page's `render` for `request`, which is passed to the page
+    /// as JSON and becomes its `req`.
     pub fn render_with_response(
-        &mut self,
+        &self,
         lib_path: &Path,
-    ) -> Result<
-        (
-            String,
-            u16,
-            Option<String>,
-            Vec<(String, String, i64)>,
-            Vec<(String, String)>,
-        ),
-        LoadError,
-    > {
-        let modified = std::fs::metadata(lib_path)?.modified()?;
+        request: &RequestData,
+    ) -> Result<PageResponse, LoadError> {
+        self.load(lib_path)?.render(request)
+    }
 
-        let needs_reload = match self.libraries.get(lib_path) {
-            Some(loaded) => loaded.modified != modified,
-            None => true,
-        };
+    /// Like `render_with_response`, but hands the output to `sink` each time
+    /// the page calls `flush()`, and once more when it returns. A panic after
+    /// the first flush is still reported, but the head has already gone out.
+    pub fn render_streaming(
+        &self,
+        lib_path: &Path,
+        request: &RequestData,
+        sink: &mut dyn FnMut(Chunk<'_>),
+    ) -> Result<(), LoadError> {
+        self.load(lib_path)?.render_streaming(request, sink)
+    }
 
-        if needs_reload {
-            if let Some(old) = self.libraries.remove(lib_path) {
-                drop(old);
+    /// The library at `lib_path`, loading it again if the file changed.
+    /// Holding on to it skips that check on later renders.
+    pub fn load(&self, lib_path: &Path) -> Result<Arc<LoadedLib>, LoadError> {
+        let modified = std::fs::metadata(lib_path)?.modified()?;
+
+        if let Some(loaded) = self.libraries.read().unwrap().get(lib_path) {
+            if loaded.modified == modified {
+                return Ok(loaded.clone());
             }
-            let library = unsafe { Library::new(lib_path) }?;
-            self.libraries
-                .insert(lib_path.to_path_buf(), LoadedLib { library, modified });
         }
 
-        let loaded = self.libraries.get(lib_path).unwrap();
-
-        let render_fn: Symbol<unsafe extern "C" fn() -> *mut std::os::raw::c_char> =
-            unsafe { loaded.library.get(b"render") }?;
-
-        let free_fn: Symbol<unsafe extern "C" fn(*mut std::os::raw::c_char)> =
-            unsafe { loaded.library.get(b"free_string") }?;
-
-        let get_status_fn: Symbol<unsafe extern "C" fn() -> u16> =
-            unsafe { loaded.library.get(b"get_status_code") }?;
-
-        let get_redirect_fn: Symbol<unsafe extern "C" fn() -> *mut std::os::raw::c_char> =
-            unsafe { loaded.library.get(b"get_redirect") }?;
-
-        let get_cookies_fn: Symbol<unsafe extern "C" fn() -> *mut std::os::raw::c_char> =
-            unsafe { loaded.library.get(b"get_cookies") }?;
-
-        let get_headers_fn: Symbol<unsafe extern "C" fn() -> *mut std::os::raw::c_char> =
-            unsafe { loaded.library.get(b"get_headers") }?;
-
-        let ptr = unsafe { render_fn() };
-        let content = unsafe {
-            let c_str = CStr::from_ptr(ptr);
-            let s = c_str.to_string_lossy().into_owned();
-            free_fn(ptr);
-            s
-        };
-
-        let status_code = unsafe { get_status_fn() };
-
-        let redirect = unsafe {
-            let ptr = get_redirect_fn();
-            if ptr.is_null() {
-                None
-            } else {
-                let c_str = CStr::from_ptr(ptr);
-                let s = c_str.to_string_lossy().into_owned();
-                free_fn(ptr);
-                if s.is_empty() {
-                    None
-                } else {
-                    Some(s)
-                }
+        let mut libraries = self.libraries.write().unwrap();
+        // Another thread may have loaded it while we waited for the lock.
+        if let Some(loaded) = libraries.get(lib_path) {
+            if loaded.modified == modified {
+                return Ok(loaded.clone());
             }
-        };
-
-        let cookies = unsafe {
-            let ptr = get_cookies_fn();
-            let s = if ptr.is_null() {
-                String::new()
-            } else {
-                let c_str = CStr::from_ptr(ptr);
-                let s = c_str.to_string_lossy().into_owned();
-                free_fn(ptr);
-                s
-            };
-            parse_cookies(&s)
-        };
-
-        let headers = unsafe {
-            let ptr = get_headers_fn();
-            let s = if ptr.is_null() {
-                String::new()
-            } else {
-                let c_str = CStr::from_ptr(ptr);
-                let s = c_str.to_string_lossy().into_owned();
-                free_fn(ptr);
-                s
-            };
-            parse_headers(&s)
-        };
+        }
+        let loaded = Arc::new(LoadedLib::open(lib_path, modified)?);
+        libraries.insert(lib_path.to_path_buf(), loaded.clone());
+        Ok(loaded)
+    }
 
-        Ok((content, status_code, redirect, cookies, headers))
+    /// Forgets the library at `lib_path`, e.g. once the page it was built
+    /// from has changed.
+    pub fn unload(&self, lib_path: &Path) {
+        self.libraries.write().unwrap().remove(lib_path);
     }
 
-    pub fn unload_all(&mut self) {
-        self.libraries.clear();
+    pub fn unload_all(&self) {
+        self.libraries.write().unwrap().clear();
     }
 }
 
-fn parse_cookies(s: &str) -> Vec<(String, String, i64)> {
-    if s.is_empty() {
-        return Vec::new();
-    }
-    s.split('\n')
-        .filter_map(|line| {
-            let parts: Vec<&str> = line.split('\t').collect();
-            if parts.len() >= 3 {
-                Some((
-                    parts[0].to_string(),
-                    parts[1].to_string(),
-                    parts[2].parse().unwrap_or(0),
-                ))
-            } else {
-                None
+/// Cookie records are `name, value, max_age, expires, path, domain,
+/// same_site, secure, http_only`, with empty fields for unset attributes.
+fn parse_cookies(s: &str) -> Vec<Cookie> {
+    records(s)
+        .filter_map(|fields| match fields.as_slice() {
+            [name, value, max_age, expires, path, domain, same_site, secure, http_only] => {
+                Some(Cookie {
+                    name: name.clone(),
+                    value: value.clone(),
+                    max_age: max_age.parse().ok(),
+                    expires: expires
+                        .parse()
+                        .ok()
+                        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                    path: Some(path.clone()).filter(|p| !p.is_empty()),
+                    domain: Some(domain.clone()).filter(|d| !d.is_empty()),
+                    same_site: SameSite::parse(same_site),
+                    secure: !secure.is_empty(),
+                    http_only: !http_only.is_empty(),
+                })
             }
+            _ => None,
         })
         .collect()
 }
 
 fn parse_headers(s: &str) -> Vec<(String, String)> {
-    if s.is_empty() {
-        return Vec::new();
-    }
-    s.split('\n')
-        .filter_map(|line| {
-            let parts: Vec<&str> = line.splitn(2, ':').collect();
-            if parts.len() == 2 {
-                Some((parts[0].to_string(), parts[1].to_string()))
-            } else {
-                None
-            }
+    records(s)
+        .filter_map(|fields| match fields.as_slice() {
+            [name, value, ..] => Some((name.clone(), value.clone())),
+            _ => None,
         })
         .collect()
 }
 
+/// Splits what a page serialized into lines of tab-separated fields.
+/// Pages escape `\`, tab, `\n` and `\r` inside fields with a backslash.
+fn records(s: &str) -> impl Iterator<Item = Vec<String>> + '_ {
+    s.split('\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| line.split('\t').map(unescape_field).collect())
+}
+
+fn unescape_field(field: &str) -> String {
+    let mut result = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some(c) => result.push(c),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
 impl Default for Loader {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::generator::{GenerateOptions, Generator};
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_parse_escaped_fields() {
+        let headers = parse_headers("Content-Type\ttext/plain\nX-Note\ta:b\\nc\\td\\\\\n");
+        assert_eq!(
+            headers,
+            vec![
+                ("Content-Type".to_string(), "text/plain".to_string()),
+                ("X-Note".to_string(), "a:b\nc\td\\".to_string()),
+            ]
+        );
+
+        let cookies =
+            parse_cookies("sid\tx\\ty\t3600\t784111777\t/app\t\tLax\t1\t\nold\tv\t60\nbroken");
+        assert_eq!(
+            cookies[0],
+            Cookie::new("sid", "x\ty")
+                .max_age(3600)
+                .expires(UNIX_EPOCH + Duration::from_secs(784111777))
+                .path("/app")
+                .same_site(SameSite::Lax)
+                .secure()
+                .http_only(false)
+        );
+        assert_eq!(
+            cookies[0].to_string(),
+            "sid=x%09y; Max-Age=3600; Expires=Sun, 06 Nov 1994 08:49:37 GMT; Path=/app; SameSite=Lax; Secure"
+        );
+        assert_eq!(cookies.len(), 1);
+    }
+
+    /// Builds `template` with its descriptor edited by `edit`, as an older
+    /// or newer rsp would have built it.
+    fn build_page(name: &str, template: &str, edit: impl Fn(String) -> String) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rsp-loader-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let parsed = Parser::new().parse(template).unwrap();
+        let generated = Generator::new()
+            .generate_with_options(&parsed, GenerateOptions::default())
+            .unwrap();
+        Compiler::new(dir)
+            .compile(&edit(generated.source), name)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_rejects_incompatible_libraries() {
+        let abi = format!("abi_version: {},", ABI_VERSION);
+        let old_abi = build_page("old-abi", "<p>hi</p>", |s| {
+            s.replace(&abi, "abi_version: 2,")
+        });
+        let runtime = format!("\"{}\\0\"", rsp_runtime::VERSION);
+        let old_runtime = build_page("old-runtime", "<p>hi</p>", |s| {
+            s.replace(&runtime, "\"0.0.0\\0\"")
+        });
+        let current = build_page("current", "<p>hi</p>", |s| s);
+
+        let loader = Loader::new();
+        match loader.load(&old_abi) {
+            Err(LoadError::Incompatible(message)) => {
+                assert_eq!(
+                    message,
+                    format!("page ABI version 2, expected {}", ABI_VERSION)
+                )
+            }
+            _ => panic!("expected an incompatible library"),
+        }
+        match loader.load(&old_runtime) {
+            Err(LoadError::Incompatible(message)) => assert_eq!(
+                message,
+                format!(
+                    "page built for rsp-runtime 0.0.0, server has {}",
+                    rsp_runtime::VERSION
+                )
+            ),
+            _ => panic!("expected an incompatible library"),
+        }
+        assert_eq!(loader.render(&current).unwrap(), b"<p>hi</p>");
+    }
+}
@@ -1,16 +1,28 @@
 use axum::{
-    body::Body,
+    body::{Body, Bytes},
     extract::Request as AxumRequest,
-    http::{header, Response, StatusCode},
-    response::IntoResponse,
+    http::{header, HeaderName, HeaderValue, Response, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    routing::get,
     Router,
 };
 use clap::Parser;
-use rsp::engine::RenderResult;
-use rsp::RspEngine;
+use rsp::engine::{RenderResult, StreamChunk};
+use rsp::error_page::render_error_page;
+use rsp::{find_page, AccessRules, PageLookup, Routes, RspEngine, RspError, Watcher};
+use rsp_runtime::{ErrorInfo, RequestData};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io::Write;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
 use tower::ServiceExt;
 use tower_http::services::ServeDir;
 use walkdir::WalkDir;
@@ -37,8 +49,90 @@ struct Cli {
 
     #[arg(long = "cache-dir", value_name = "DIR")]
     cache_dir: Option<PathBuf>,
+
+    #[arg(long = "production")]
+    production: bool,
+
+    /// Page rendered when a page fails, used if it exists in the docroot.
+    #[arg(long = "error-page", value_name = "FILE", default_value = "500.rsp")]
+    error_page: String,
+
+    /// Page rendered for 404s, used if it exists in the docroot.
+    #[arg(long = "not-found-page", value_name = "FILE", default_value = "404.rsp")]
+    not_found_page: String,
+
+    /// Page rendered for refused paths, used if it exists in the docroot.
+    #[arg(long = "forbidden-page", value_name = "FILE", default_value = "403.rsp")]
+    forbidden_page: String,
+
+    #[arg(
+        long = "max-builds",
+        value_name = "N",
+        default_value_t = rsp::engine::DEFAULT_MAX_BUILDS
+    )]
+    max_builds: usize,
+
+    /// Send page output as it is flushed instead of all at once.
+    #[arg(long = "stream")]
+    stream: bool,
+
+    /// Page that handles every request not matching a file or route.
+    #[arg(long = "router", value_name = "FILE")]
+    router: Option<String>,
+
+    /// Route table in the docroot, used if it exists.
+    #[arg(long = "routes", value_name = "FILE", default_value = "routes.toml")]
+    routes: String,
+
+    /// Redirect `/post.rsp` to `/post` and `/dir/index.rsp` to `/dir/`.
+    #[arg(long = "canonical-urls")]
+    canonical_urls: bool,
+
+    /// Refuse paths matching PATTERN, on top of the default deny list.
+    #[arg(long = "deny", value_name = "PATTERN")]
+    deny: Vec<String>,
+
+    /// Serve paths matching PATTERN even if the deny list refuses them.
+    #[arg(long = "allow", value_name = "PATTERN")]
+    allow: Vec<String>,
+
+    /// Rebuild pages as they change and reload open browser tabs.
+    #[arg(long = "watch")]
+    watch: bool,
+
+    /// Don't check compiled pages for changes on each request.
+    #[arg(long = "no-check-changes")]
+    no_check_changes: bool,
 }
 
+/// Settings shared by every request the server handles.
+struct ServerConfig {
+    docroot: PathBuf,
+    index: String,
+    /// Hide error details and the developer error page.
+    production: bool,
+    /// Error pages in the docroot, used if they exist.
+    error_page: String,
+    not_found_page: String,
+    forbidden_page: String,
+    /// Stream page output; `flush()` only has an effect with this on.
+    stream: bool,
+    /// Front controller, relative to the docroot.
+    router: Option<String>,
+    routes: Routes,
+    /// Redirect `.rsp` URLs to their extensionless form.
+    canonical_urls: bool,
+    access: AccessRules,
+    /// Set with `--watch`; tells open pages to reload after a rebuild.
+    live_reload: Option<broadcast::Sender<()>>,
+}
+
+/// Path of the server-sent events that trigger a reload in `--watch` mode.
+const LIVE_RELOAD_PATH: &str = "/__rsp/livereload";
+
+/// Added to HTML responses in `--watch` mode.
+const LIVE_RELOAD_SCRIPT: &str = "<script>new EventSource(\"/__rsp/livereload\").addEventListener(\"reload\", () => location.reload());</script>\n";
+
 fn main() {
     let cli = Cli::parse();
 
@@ -50,13 +144,66 @@ fn main() {
     );
     
     engine.set_docroot(docroot.clone());
+    engine.set_max_builds(cli.max_builds);
+    engine.set_check_changes(!cli.no_check_changes);
 
     rsp::engine::register_cleanup(engine.clone());
 
     let runtime = tokio::runtime::Runtime::new().expect("Failed to create tokio runtime");
 
     if let Some(addr) = cli.server {
-        runtime.block_on(run_server(engine.clone(), &docroot, &addr, &cli.index));
+        let routes_file = docroot.join(&cli.routes);
+        let routes = if routes_file.is_file() {
+            Routes::load(&routes_file).unwrap_or_else(|e| {
+                eprintln!("{}: {}", routes_file.display(), e);
+                std::process::exit(1);
+            })
+        } else {
+            Routes::default()
+        };
+        let router = cli.router.map(|r| r.trim_start_matches('/').to_string());
+        if let Some(router) = &router {
+            if !docroot.join(router).is_file() {
+                eprintln!("Router page {} not found in {}", router, docroot.display());
+                std::process::exit(1);
+            }
+        }
+        let mut access = AccessRules::new(&docroot, &cache_dir);
+        access.deny.extend(cli.deny.iter().cloned());
+        access.allow.extend(cli.allow.iter().cloned());
+
+        // Keep the watcher alive for as long as the server runs.
+        let mut _watcher = None;
+        let live_reload = if cli.watch {
+            let (reload_tx, _) = broadcast::channel(16);
+            let tx = reload_tx.clone();
+            let watcher = Watcher::start(engine.clone(), &docroot, move || {
+                let _ = tx.send(());
+            })
+            .unwrap_or_else(|e| {
+                eprintln!("Cannot watch {}: {}", docroot.display(), e);
+                std::process::exit(1);
+            });
+            _watcher = Some(watcher);
+            Some(reload_tx)
+        } else {
+            None
+        };
+        let config = ServerConfig {
+            docroot: docroot.clone(),
+            index: cli.index.clone(),
+            production: cli.production,
+            error_page: cli.error_page.clone(),
+            not_found_page: cli.not_found_page.clone(),
+            forbidden_page: cli.forbidden_page.clone(),
+            stream: cli.stream,
+            router,
+            routes,
+            canonical_urls: cli.canonical_urls,
+            access,
+            live_reload,
+        };
+        runtime.block_on(run_server(engine.clone(), config, &addr));
     } else if let Some(file) = cli.file {
         run_file(&engine, &file);
     } else {
@@ -70,47 +217,69 @@ fn main() {
     engine.unload_all();
 }
 
-fn run_file(engine: &Arc<RspEngine>, file: &PathBuf) {
+fn run_file(engine: &Arc<RspEngine>, file: &Path) {
     match engine.render_file(file) {
         Ok(result) => {
             if let Some(redirect) = &result.redirect {
                 println!("Redirect: {}", redirect);
             }
-            println!("{}", result.content);
+            let mut stdout = std::io::stdout().lock();
+            let _ = stdout.write_all(&result.content);
+            let _ = stdout.write_all(b"\n");
         }
         Err(e) => {
-            eprintln!("Error: {}", e);
+            eprintln!("{}", e);
             std::process::exit(1);
         }
     }
 }
 
-async fn run_server(engine: Arc<RspEngine>, docroot: &Path, addr: &str, index: &str) {
+async fn run_server(engine: Arc<RspEngine>, config: ServerConfig, addr: &str) {
     let addr: SocketAddr = addr.parse().unwrap_or_else(|_| {
         eprintln!("Invalid address format, using 127.0.0.1:8080");
         "127.0.0.1:8080".parse().unwrap()
     });
 
     println!("RSP development server started");
-    println!("Document root: {}", docroot.display());
-    println!("Index file: {}", index);
+    println!("Document root: {}", config.docroot.display());
+    println!("Index file: {}", config.index);
+    if config.production {
+        println!("Production mode: error details are hidden");
+    }
+    if !config.routes.is_empty() {
+        println!("Routes: {} routes loaded", config.routes.len());
+    }
+    if let Some(router) = &config.router {
+        println!("Router: {}", router);
+    }
+    if config.live_reload.is_some() {
+        println!("Watching for changes; pages reload when rebuilt");
+    }
     println!("Listening on http://{}", addr);
     println!("Press Ctrl+C to stop");
 
-    let docroot_clone = docroot.to_path_buf();
-    let serve_dir = ServeDir::new(docroot_clone.clone());
-    let index_clone = index.to_string();
-
-    let app = Router::new()
-        .fallback(move |req| {
-            let engine = engine.clone();
-            let docroot = docroot_clone.clone();
-            let serve_dir = serve_dir.clone();
-            let index = index_clone.clone();
-            async move {
-                handle_request(req, engine, docroot, serve_dir, &index).await
+    let serve_dir = ServeDir::new(config.docroot.clone());
+    let config = Arc::new(config);
+
+    let mut app = Router::new();
+    if let Some(reload_tx) = config.live_reload.clone() {
+        app = app.route(LIVE_RELOAD_PATH, get(move || live_reload_events(reload_tx.clone())));
+    }
+    let app = app.fallback(move |req| {
+        let engine = engine.clone();
+        let config = config.clone();
+        let serve_dir = serve_dir.clone();
+        async move {
+            let response = handle_request(req, engine, config.clone(), serve_dir)
+                .await
+                .into_response();
+            if config.live_reload.is_some() {
+                inject_live_reload(response).await
+            } else {
+                response
             }
-        });
+        }
+    });
 
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     if let Err(e) = axum::serve(listener, app).await {
@@ -121,13 +290,12 @@ async fn run_server(engine: Arc<RspEngine>, docroot: &Path, addr: &str, index: &
 async fn handle_request(
     axum_req: AxumRequest,
     engine: Arc<RspEngine>,
-    docroot: PathBuf,
+    config: Arc<ServerConfig>,
     serve_dir: ServeDir,
-    default_index: &str,
 ) -> impl IntoResponse {
+    let docroot = &config.docroot;
     let uri = axum_req.uri().clone();
     let method = axum_req.method().to_string();
-    let mut path = uri.path().trim_start_matches('/').to_string();
     let query = uri.query().unwrap_or("").to_string();
     
     // Extract HTTP headers
@@ -135,58 +303,93 @@ async fn handle_request(
     
     let body_bytes = axum::body::to_bytes(axum_req.into_body(), 1024 * 1024 * 10).await;
     let body = body_bytes.map(|b| String::from_utf8_lossy(&b).to_string()).unwrap_or_default();
+
+    let mut request = RequestData {
+        method: method.clone(),
+        path: uri.path().to_string(),
+        query: query.clone(),
+        headers: headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((name.to_string(), value.to_str().ok()?.to_string()))
+            })
+            .collect(),
+        body,
+        route: HashMap::new(),
+        error: None,
+    };
+
+    // Decoded and normalized; nothing outside the docroot or on the deny list.
+    let path = match config.access.check(uri.path()) {
+        Ok(path) => path,
+        Err(e) => {
+            return status_page(StatusCode::FORBIDDEN, e.to_string(), request, engine, config).await
+        }
+    };
     
-    // Handle directory request - redirect to index
-    if path.is_empty() || path.ends_with('/') {
-        path = if path.is_empty() {
-            default_index.to_string()
-        } else {
-            format!("{}{}", path, default_index)
-        };
-    }
-    
-    // Check if it's an RSP file
-    if path.ends_with(".rsp") {
+    // Existing pages run directly; other paths may be routed to a page.
+    let lookup = find_page(&config.docroot, &config.index, config.canonical_urls, &path, &method);
+    let page = match lookup {
+        PageLookup::Page(path) => Some((path, HashMap::new())),
+        PageLookup::Redirect(location) => {
+            let location = if query.is_empty() {
+                location
+            } else {
+                format!("{}?{}", location, query)
+            };
+            return Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(header::LOCATION, location)
+                .body(Body::empty())
+                .unwrap();
+        }
+        PageLookup::NoPage => route_page(&config, &path, uri.path()),
+    };
+
+    if let Some((path, route)) = page {
         let file_path = docroot.join(&path);
-        
-        if file_path.exists() {
-            // Set up environment variables for request
-            std::env::set_var("REQUEST_METHOD", &method);
-            std::env::set_var("QUERY_STRING", &query);
-            std::env::set_var("REQUEST_URI", &path);
-            std::env::set_var("RSP_BODY", &body);
-            
-            // Set HTTP headers as environment variables (HTTP_* format)
-            for (name, value) in headers.iter() {
-                let env_key = format!("HTTP_{}", name.as_str().replace('-', "_").to_uppercase());
-                if let Ok(v) = value.to_str() {
-                    std::env::set_var(&env_key, v);
-                }
-            }
-            
-            match engine.render_file_with_body(&file_path, &body) {
-                Ok(result) => {
-                    return build_response(result);
-                }
+        request.route = route;
+
+        if config.stream {
+            return stream_page(file_path, request, path, engine, config).await;
+        }
+
+        // Pages run user code that may block (database queries and the
+        // like), so keep them off the async workers.
+        let rendered = tokio::task::spawn_blocking(move || {
+            match engine.render_file_with_request(&file_path, &request) {
+                Ok(result) => build_response(result),
                 Err(e) => {
-                    return Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
-                        .body(Body::from(format!("Error: {}", e)))
-                        .unwrap();
+                    eprintln!("Error rendering /{}: {}", path, e);
+                    error_response(&e, &path, request, &engine, &config)
                 }
             }
-        }
+        })
+        .await;
+        return rendered.unwrap_or_else(|e| {
+            eprintln!("Render task failed: {}", e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("Internal Server Error"))
+                .unwrap()
+        });
     }
     
     // Serve static files
+    if let Err(e) = config.access.check_static(&path) {
+        return status_page(StatusCode::FORBIDDEN, e.to_string(), request, engine, config).await;
+    }
     let req = AxumRequest::builder()
         .method(method.as_str())
         .uri(uri)
-        .body(Body::from(body))
+        .body(Body::from(request.body.clone()))
         .unwrap();
     
     match serve_dir.oneshot(req).await {
+        Ok(res) if res.status() == StatusCode::NOT_FOUND => {
+            let details = format!("No page, route or file matches {}", path);
+            status_page(StatusCode::NOT_FOUND, details, request, engine, config).await
+        }
         Ok(res) => res.map(Body::new),
         Err(_) => Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -195,37 +398,273 @@ async fn handle_request(
     }
 }
 
+/// Sends a `reload` event to the page every time the watcher has rebuilt
+/// the docroot.
+async fn live_reload_events(
+    reload_tx: broadcast::Sender<()>,
+) -> impl IntoResponse {
+    let mut reload_rx = reload_tx.subscribe();
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Event, Infallible>>(1);
+    tokio::spawn(async move {
+        // Lagging behind only means several rebuilds became one reload.
+        while let Ok(()) | Err(broadcast::error::RecvError::Lagged(_)) = reload_rx.recv().await {
+            if tx.send(Ok(Event::default().event("reload").data(""))).await.is_err() {
+                break;
+            }
+        }
+    });
+    Sse::new(ReceiverStream::new(rx)).keep_alive(KeepAlive::default())
+}
+
+/// Adds the live-reload script to an HTML response: before `</body>` when
+/// the whole body is at hand, at the end of a streamed one.
+async fn inject_live_reload(response: Response<Body>) -> Response<Body> {
+    let is_html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"));
+    let size = axum::body::HttpBody::size_hint(response.body()).exact();
+    if !is_html || size == Some(0) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let script = Bytes::from_static(LIVE_RELOAD_SCRIPT.as_bytes());
+    let body = match size {
+        Some(_) => {
+            let Ok(mut content) = axum::body::to_bytes(body, usize::MAX).await.map(Vec::from) else {
+                return plain_status(StatusCode::INTERNAL_SERVER_ERROR);
+            };
+            let at = content
+                .windows(7)
+                .rposition(|w| w.eq_ignore_ascii_case(b"</body>"))
+                .unwrap_or(content.len());
+            content.splice(at..at, script);
+            Body::from(content)
+        }
+        None => Body::from_stream(
+            body.into_data_stream()
+                .chain(tokio_stream::once(Ok(script))),
+        ),
+    };
+    Response::from_parts(parts, body)
+}
+
+/// The page for a request that names no page or file: a match in
+/// `routes.toml`, else the `--router` page. Either only if the page exists;
+/// otherwise the request ends in a 404. Static files always win, like
+/// PHP's `public/index.php` setups. `path` is the checked path, `uri_path`
+/// the raw one, whose segments the routes decode themselves. Returns the
+/// page relative to the docroot and the route parameters.
+fn route_page(
+    config: &ServerConfig,
+    path: &str,
+    uri_path: &str,
+) -> Option<(String, HashMap<String, String>)> {
+    if config.docroot.join(path.trim_start_matches('/')).is_file() {
+        return None;
+    }
+    if let Some(found) = config.routes.resolve(uri_path) {
+        let page = found.page.to_string_lossy().into_owned();
+        return config
+            .docroot
+            .join(&page)
+            .is_file()
+            .then_some((page, found.params));
+    }
+    config
+        .router
+        .clone()
+        .filter(|router| config.docroot.join(router).is_file())
+        .map(|router| (router, HashMap::new()))
+}
+
+/// Responds with `status` through its error page, see `custom_error_page`.
+async fn status_page(
+    status: StatusCode,
+    details: String,
+    request: RequestData,
+    engine: Arc<RspEngine>,
+    config: Arc<ServerConfig>,
+) -> Response<Body> {
+    tokio::task::spawn_blocking(move || {
+        custom_error_page(status, details, request, &engine, &config)
+            .unwrap_or_else(|| plain_status(status))
+    })
+    .await
+    .unwrap_or_else(|_| plain_status(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// Renders the docroot's page for `status` (`404.rsp`, `403.rsp` or
+/// `500.rsp` unless configured otherwise) for the original request, with
+/// `error` describing what happened. `details` only reach the page in dev
+/// mode. `None` if there is no such page or it fails itself.
+fn custom_error_page(
+    status: StatusCode,
+    details: String,
+    mut request: RequestData,
+    engine: &RspEngine,
+    config: &ServerConfig,
+) -> Option<Response<Body>> {
+    let page = match status {
+        StatusCode::NOT_FOUND => &config.not_found_page,
+        StatusCode::FORBIDDEN => &config.forbidden_page,
+        _ => &config.error_page,
+    };
+    let file = config.docroot.join(page);
+    if !file.is_file() {
+        return None;
+    }
+    request.error = Some(ErrorInfo {
+        status: status.as_u16(),
+        path: request.path.clone(),
+        details: (!config.production).then_some(details),
+    });
+    match engine.render_file_with_request(&file, &request) {
+        Ok(mut result) => {
+            if result.status_code == 200 {
+                result.status_code = status.as_u16();
+            }
+            Some(build_response(result))
+        }
+        Err(e) => {
+            eprintln!("Error rendering {}: {}", page, e);
+            None
+        }
+    }
+}
+
+fn plain_status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(status.to_string()))
+        .unwrap()
+}
+
+/// Renders a page with its body streamed to the client as the page flushes.
+/// The response starts at the first flush, so an error before that still
+/// gets the usual error page; one after it cuts the response short.
+async fn stream_page(
+    file_path: PathBuf,
+    request: RequestData,
+    path: String,
+    engine: Arc<RspEngine>,
+    config: Arc<ServerConfig>,
+) -> Response<Body> {
+    // The head arrives as `Ok` and the body follows on `body_rx`; a
+    // complete error response arrives as `Err`.
+    let (head_tx, head_rx) = tokio::sync::oneshot::channel();
+    let (body_tx, body_rx) = tokio::sync::mpsc::channel::<Result<Bytes, std::io::Error>>(16);
+
+    tokio::task::spawn_blocking(move || {
+        let mut head_tx = Some(head_tx);
+        let result = engine.render_file_streaming(&file_path, &request, &mut |chunk| match chunk {
+            StreamChunk::Head(head) => {
+                if let Some(tx) = head_tx.take() {
+                    let _ = tx.send(Ok(build_response(head)));
+                }
+            }
+            StreamChunk::Body(bytes) => {
+                // The client may have gone away; the page still runs to the end.
+                let _ = body_tx.blocking_send(Ok(Bytes::copy_from_slice(bytes)));
+            }
+        });
+        if let Err(e) = result {
+            eprintln!("Error rendering /{}: {}", path, e);
+            match head_tx.take() {
+                Some(tx) => {
+                    let response = error_response(&e, &path, request.clone(), &engine, &config);
+                    let _ = tx.send(Err(response));
+                }
+                None => {
+                    let _ = body_tx.blocking_send(Err(std::io::Error::other(e.to_string())));
+                }
+            }
+        }
+    });
+
+    match head_rx.await {
+        Ok(Ok(head)) => head.map(|_| Body::from_stream(ReceiverStream::new(body_rx))),
+        Ok(Err(error_page)) => error_page,
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from("Internal Server Error"))
+            .unwrap(),
+    }
+}
+
+/// The configured error page if there is one; otherwise the developer error
+/// page in dev mode, or a bare 500 in production.
+fn error_response(
+    error: &RspError,
+    path: &str,
+    request: RequestData,
+    engine: &RspEngine,
+    config: &ServerConfig,
+) -> Response<Body> {
+    let status = StatusCode::INTERNAL_SERVER_ERROR;
+    if let Some(response) = custom_error_page(status, error.to_string(), request, engine, config) {
+        return response;
+    }
+
+    if !config.production {
+        return Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(render_error_page(error, &format!("/{}", path))))
+            .unwrap();
+    }
+    plain_status(status)
+}
+
 fn build_response(result: RenderResult) -> Response<Body> {
     let status = StatusCode::from_u16(result.status_code).unwrap_or(StatusCode::OK);
     
-    let mut builder = Response::builder()
-        .status(status)
-        .header(header::CONTENT_TYPE, "text/html; charset=utf-8");
+    let mut builder = Response::builder().status(status);
+    let has_content_type = result
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("content-type"));
+    if !has_content_type {
+        builder = builder.header(header::CONTENT_TYPE, "text/html; charset=utf-8");
+    }
     
     // Handle redirect
     if let Some(redirect) = &result.redirect {
-        builder = builder.header(header::LOCATION, redirect);
+        match HeaderValue::from_str(redirect) {
+            Ok(value) => builder = builder.header(header::LOCATION, value),
+            Err(_) => eprintln!("Ignoring invalid redirect {:?}", redirect),
+        }
     }
     
     // Set cookies
-    for (name, value, max_age) in &result.cookies {
-        let cookie_str = if *max_age < 0 {
-            format!("{}=; Path=/; Max-Age=0; HttpOnly", name)
-        } else {
-            format!("{}={}; Path=/; Max-Age={}{}; HttpOnly", 
-                name, value, max_age,
-                if *max_age > 0 { "" } else { "" }
-            )
-        };
-        builder = builder.header(header::SET_COOKIE, cookie_str);
+    for cookie in &result.cookies {
+        match HeaderValue::from_str(&cookie.to_string()) {
+            Ok(value) if cookie.has_valid_name() => {
+                builder = builder.header(header::SET_COOKIE, value)
+            }
+            _ => eprintln!("Ignoring cookie with invalid name {:?}", cookie.name),
+        }
     }
     
     // Set custom headers
     for (name, value) in &result.headers {
-        builder = builder.header(name.as_str(), value.as_str());
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            (Ok(name), Ok(value)) => builder = builder.header(name, value),
+            _ => eprintln!("Ignoring invalid response header {:?}: {:?}", name, value),
+        }
     }
     
-    builder.body(Body::from(result.content)).unwrap()
+    builder.body(Body::from(result.content)).unwrap_or_else(|e| {
+        eprintln!("Invalid response: {}", e);
+        plain_status(StatusCode::INTERNAL_SERVER_ERROR)
+    })
 }
 
 fn precompile_all(engine: &Arc<RspEngine>, docroot: &Path) {
@@ -240,7 +679,7 @@ fn precompile_all(engine: &Arc<RspEngine>, docroot: &Path) {
         .filter_map(|e| e.ok())
     {
         let path = entry.path();
-        if path.extension().map_or(false, |ext| ext == "rsp") {
+        if path.extension().is_some_and(|ext| ext == "rsp") {
             print!("Compiling {}... ", path.display());
             match engine.render_file(path) {
                 Ok(_) => {
@@ -273,6 +712,20 @@ Options:
   -i, --index <FILE>              Default index file (default: index.rsp)
       --precompile                Precompile all .rsp files
       --cache-dir <DIR>           Cache directory (default: .rspcache)
+      --production                Hide error details from visitors
+      --error-page <FILE>         Page served when a page fails (default: 500.rsp)
+      --not-found-page <FILE>     Page served for 404s (default: 404.rsp)
+      --forbidden-page <FILE>     Page served for refused paths (default: 403.rsp)
+      --max-builds <N>            Pages compiled at the same time (default: 2)
+      --stream                    Send page output as it is flushed
+      --router <FILE>             Page handling requests that match no file
+      --routes <FILE>             Route table (default: routes.toml, if present)
+      --canonical-urls            Redirect /page.rsp to /page
+      --deny <PATTERN>            Also refuse matching paths (e.g. "*.bak", "private/*")
+      --allow <PATTERN>           Serve matching paths despite the deny list
+      --watch                     Rebuild pages on change and reload the browser
+      --no-check-changes          Don't stat pages on each request (for deploys
+                                  where files don't change while running)
 
 Examples:
   rsp hello.rsp                   Run hello.rsp and print output
@@ -282,11 +735,18 @@ Examples:
 
 Template syntax:
   <% code %>                      Execute Rust code
-  <%= expression %>               Output expression value
+  <%= expression %>               Output expression value, HTML-escaped
+  <%== expression %>              Output expression value as-is
   <%! static ... %>               Static declarations (run once)
   <%@ use ... %>                  Import module
   <%@ dep ... %>                  Add dependency
   <%@ once_cell %>                Enable lazy static initialization
+  <%@ include "file.rsp" %>       Inline another template at compile time
+  <%@ extends "layout.rsp" %>     Render this page inside a layout
+  <%@ block name %>...<%@ endblock %>  Define or override a layout block
+  <%@ autoescape off %>           Don't escape <%= %> in this file
+  <%-- comment --%>               Template comment, not sent to the client
+  <%- ... -%>                     Trim indentation before / newline after a tag
 
 Request API:
   req.get["key"]                  GET parameter (returns &str)
@@ -296,12 +756,27 @@ Request API:
   req.method()                    Request method
   req.path()                      Request path
   req.is_post() / req.is_get()    Check method
+  req.error                       Some(error) on 404/403/500 pages
+                                  (error.status, error.path, error.details)
 
 Response API:
   header(302)                     Set status code (100-599)
   header_url("/login")            Redirect to URL (302)
   SetCookie("name", "value", 3600)  Set cookie (max_age in seconds)
   CleanCookie("name")             Delete cookie
+  cookie("sid", v).same_site(Lax).secure().path("/app")
+                                  Cookie builder (also .domain, .max_age,
+                                  .expires, .http_only(false))
+  set_header("name", "value")     Set (or replace) a response header
+  remove_header("name")           Remove a response header
+  content_type("text/plain")      Override the default text/html Content-Type
+  write_bytes(&data)              Write raw bytes (images, downloads, ...)
+  flush()                         Send output so far right away (with --stream)
+  exit() / exit_with(403)         Stop the page, keeping headers and output
+  halt(false)                     Stop the page and drop unsent output
+  ob_start() / ob_get_clean()     Capture output into a String (nestable)
+  ob_get_clean_bytes()            Same, as raw bytes (for write_bytes output)
+  ob_end_flush() / ob_get_level() End a buffer keeping its output / nesting depth
 
 Database:
   <%@ dep rusqlite = {{ version = "0.32", features = ["bundled"] }} %>
@@ -1,12 +1,80 @@
+use crate::source_map::numbered_lines;
 use std::fmt;
 
+/// Directive names the generator understands.
+pub const DIRECTIVES: &[&str] = &[
+    "use",
+    "dep",
+    "once_cell",
+    "rusqlite",
+    "include",
+    "extends",
+    "block",
+    "endblock",
+    "parent",
+    "autoescape",
+];
+
+/// Location of a token's content in the template it was parsed from.
+/// `start`/`end` are byte offsets of the trimmed content; `line` and
+/// `column` (both 1-based, column counted in characters) point at `start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    /// Which file the token came from: 0 for the template handed to the
+    /// generator, `n` for the `n`-th file it pulled in through include or
+    /// extends. Always 0 straight out of the parser.
+    pub source: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, column: usize) -> Self {
+        Span {
+            start,
+            end,
+            line,
+            column,
+            source: 0,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
-    Text(String),
-    Code(String),
-    Expression(String),
-    Directive(String),
-    Declaration(String),
+    Text(String, Span),
+    Code(String, Span),
+    Expression(String, Span),
+    /// `<%== expr %>`: output written without HTML escaping.
+    RawExpression(String, Span),
+    Directive(String, Span),
+    Declaration(String, Span),
+}
+
+impl Token {
+    pub fn span(&self) -> Span {
+        match self {
+            Token::Text(_, span)
+            | Token::Code(_, span)
+            | Token::Expression(_, span)
+            | Token::RawExpression(_, span)
+            | Token::Directive(_, span)
+            | Token::Declaration(_, span) => *span,
+        }
+    }
+
+    pub fn span_mut(&mut self) -> &mut Span {
+        match self {
+            Token::Text(_, span)
+            | Token::Code(_, span)
+            | Token::Expression(_, span)
+            | Token::RawExpression(_, span)
+            | Token::Directive(_, span)
+            | Token::Declaration(_, span) => span,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -14,10 +82,76 @@ pub struct ParsedTemplate {
     pub tokens: Vec<Token>,
     pub directives: Vec<String>,
     pub declarations: Vec<String>,
+    /// The template text the tokens were parsed from.
+    pub source: String,
 }
 
 pub struct Parser;
 
+/// Walks the template one character at a time, keeping track of the byte
+/// offset and the line/column of the next character.
+struct Cursor<'a> {
+    input: &'a str,
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(input: &'a str) -> Self {
+        Cursor {
+            input,
+            pos: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let ch = self.peek()?;
+        self.pos += ch.len_utf8();
+        if ch == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(ch)
+    }
+
+    fn span_from(&self, start: (usize, usize, usize)) -> Span {
+        Span::new(start.0, self.pos, start.1, start.2)
+    }
+
+    fn mark(&self) -> (usize, usize, usize) {
+        (self.pos, self.line, self.column)
+    }
+}
+
+/// Span of `raw[..]` trimmed, where `raw` starts at `start` in the input.
+fn trimmed_span(raw: &str, start: (usize, usize, usize)) -> Span {
+    let leading = &raw[..raw.len() - raw.trim_start().len()];
+    let (mut line, mut column) = (start.1, start.2);
+    for ch in leading.chars() {
+        if ch == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+    let begin = start.0 + leading.len();
+    Span::new(begin, begin + raw.trim().len(), line, column)
+}
+
 impl Parser {
     pub fn new() -> Self {
         Parser
@@ -27,78 +161,155 @@ impl Parser {
         let mut tokens = Vec::new();
         let mut directives = Vec::new();
         let mut declarations = Vec::new();
-        let mut chars = input.chars().peekable();
+        let mut cursor = Cursor::new(input);
         let mut text_buf = String::new();
+        let mut text_start = cursor.mark();
+        // Net `{` nesting of the code blocks seen so far, to catch
+        // declarations written inside a loop or `if`.
+        let mut depth: i64 = 0;
 
-        while let Some(ch) = chars.next() {
+        while let Some(ch) = cursor.bump() {
             if ch == '<' {
-                if let Some(&'<') = chars.peek() {
+                if let Some('<') = cursor.peek() {
                     text_buf.push('<');
-                    chars.next();
+                    cursor.bump();
                     continue;
                 }
-                if let Some(&'%') = chars.peek() {
-                    chars.next();
+                if let Some('%') = cursor.peek() {
+                    let tag_start = (cursor.pos - 1, cursor.line, cursor.column - 1);
+                    cursor.bump();
+
+                    // `<%-- ... --%>` is dropped along with its content.
+                    let comment = cursor.rest().starts_with("--");
+                    let mut text_end = tag_start.0;
+                    // `<%-` strips the indentation in front of the tag.
+                    if !comment && cursor.peek() == Some('-') {
+                        cursor.bump();
+                        let kept = text_buf.trim_end_matches([' ', '\t']).len();
+                        text_end -= text_buf.len() - kept;
+                        text_buf.truncate(kept);
+                    }
 
                     if !text_buf.is_empty() {
-                        tokens.push(Token::Text(text_buf.clone()));
-                        text_buf.clear();
+                        let span = Span::new(text_start.0, text_end, text_start.1, text_start.2);
+                        tokens.push(Token::Text(std::mem::take(&mut text_buf), span));
                     }
 
-                    let tag_type = match chars.peek() {
-                        Some(&'=') => {
-                            chars.next();
-                            TagType::Expression
+                    if comment {
+                        if !skip_comment(&mut cursor) {
+                            return Err(ParseError::UnclosedTag(ParseLocation::new(
+                                input,
+                                tag_start.1,
+                                tag_start.2,
+                                4,
+                            )));
                         }
-                        Some(&'@') => {
-                            chars.next();
+                        text_start = cursor.mark();
+                        continue;
+                    }
+
+                    let tag_type = match cursor.peek() {
+                        Some('=') => {
+                            cursor.bump();
+                            if cursor.peek() == Some('=') {
+                                cursor.bump();
+                                TagType::RawExpression
+                            } else {
+                                TagType::Expression
+                            }
+                        }
+                        Some('@') => {
+                            cursor.bump();
                             TagType::Directive
                         }
-                        Some(&'!') => {
-                            chars.next();
+                        Some('!') => {
+                            cursor.bump();
                             TagType::Declaration
                         }
                         _ => TagType::Code,
                     };
 
-                    let mut code_buf = String::new();
-
-                    loop {
-                        match chars.next() {
-                            None => {
-                                return Err(ParseError::UnclosedTag);
-                            }
-                            Some('%') => {
-                                if let Some(&'>') = chars.peek() {
-                                    chars.next();
-                                    break;
-                                }
-                                code_buf.push('%');
-                            }
-                            Some(c) => {
-                                code_buf.push(c);
-                            }
-                        }
+                    let content_start = cursor.mark();
+                    let scanned = match tag_type {
+                        TagType::Directive => scan_directive(&mut cursor),
+                        _ => scan_rust(&mut cursor),
+                    };
+                    let Some((mut code_buf, braces)) = scanned else {
+                        return Err(ParseError::UnclosedTag(ParseLocation::new(
+                            input,
+                            tag_start.1,
+                            tag_start.2,
+                            2,
+                        )));
+                    };
+                    // `-%>` swallows the rest of the line, newline included.
+                    let trim_after = code_buf.ends_with('-');
+                    if trim_after {
+                        code_buf.pop();
                     }
 
+                    let span = trimmed_span(&code_buf, content_start);
                     let content = code_buf.trim().to_string();
+                    let tag_width = if cursor.line == tag_start.1 {
+                        cursor.column - tag_start.2
+                    } else {
+                        3
+                    };
 
                     match tag_type {
-                        TagType::Expression => {
-                            tokens.push(Token::Expression(content));
+                        TagType::Expression | TagType::RawExpression => {
+                            if content.is_empty() {
+                                return Err(ParseError::EmptyExpression(ParseLocation::new(
+                                    input,
+                                    tag_start.1,
+                                    tag_start.2,
+                                    tag_width,
+                                )));
+                            }
+                            if let TagType::RawExpression = tag_type {
+                                tokens.push(Token::RawExpression(content, span));
+                            } else {
+                                tokens.push(Token::Expression(content, span));
+                            }
                         }
                         TagType::Code => {
-                            tokens.push(Token::Code(content));
+                            depth += braces;
+                            tokens.push(Token::Code(content, span));
                         }
                         TagType::Directive => {
+                            let name = content.split_whitespace().next().unwrap_or("");
+                            if !DIRECTIVES.contains(&name) {
+                                let (line, column, width) = if name.is_empty() {
+                                    (tag_start.1, tag_start.2, tag_width)
+                                } else {
+                                    (span.line, span.column, name.chars().count())
+                                };
+                                return Err(ParseError::UnknownDirective {
+                                    name: name.to_string(),
+                                    location: ParseLocation::new(input, line, column, width),
+                                });
+                            }
                             directives.push(content.clone());
-                            tokens.push(Token::Directive(content));
+                            tokens.push(Token::Directive(content, span));
                         }
                         TagType::Declaration => {
+                            if depth > 0 {
+                                return Err(ParseError::MisplacedDeclaration(ParseLocation::new(
+                                    input,
+                                    tag_start.1,
+                                    tag_start.2,
+                                    3,
+                                )));
+                            }
                             declarations.push(content.clone());
-                            tokens.push(Token::Declaration(content));
+                            tokens.push(Token::Declaration(content, span));
                         }
                     }
+
+                    if trim_after {
+                        skip_line_end(&mut cursor);
+                    }
+                    text_start = cursor.mark();
                 } else {
                     text_buf.push(ch);
                 }
@@ -108,13 +319,15 @@ impl Parser {
         }
 
         if !text_buf.is_empty() {
-            tokens.push(Token::Text(text_buf));
+            let span = cursor.span_from(text_start);
+            tokens.push(Token::Text(text_buf, span));
         }
 
         Ok(ParsedTemplate {
             tokens,
             directives,
             declarations,
+            source: input.to_string(),
         })
     }
 }
@@ -122,6 +335,7 @@ impl Parser {
 enum TagType {
     Code,
     Expression,
+    RawExpression,
     Directive,
     Declaration,
 }
@@ -132,16 +346,298 @@ impl Default for Parser {
     }
 }
 
-#[derive(Debug)]
+/// Skips the body of a `<%-- ... --%>` comment, returning `false` if it is
+/// never closed.
+fn skip_comment(cursor: &mut Cursor) -> bool {
+    cursor.bump();
+    cursor.bump();
+    while !cursor.rest().starts_with("--%>") {
+        if cursor.bump().is_none() {
+            return false;
+        }
+    }
+    for _ in 0..4 {
+        cursor.bump();
+    }
+    true
+}
+
+/// Skips spaces and tabs up to and including the next newline. Nothing is
+/// skipped if other text comes first.
+fn skip_line_end(cursor: &mut Cursor) {
+    let rest = cursor.rest();
+    let blanks = rest.len() - rest.trim_start_matches([' ', '\t']).len();
+    let after = &rest[blanks..];
+    let newline = if after.starts_with("\r\n") {
+        2
+    } else if after.starts_with('\n') {
+        1
+    } else {
+        return;
+    };
+    for _ in 0..blanks + newline {
+        cursor.bump();
+    }
+}
+
+/// Reads a directive body up to the first `%>`.
+fn scan_directive(cursor: &mut Cursor) -> Option<(String, i64)> {
+    let mut buf = String::new();
+    while !cursor.rest().starts_with("%>") {
+        buf.push(cursor.bump()?);
+    }
+    cursor.bump();
+    cursor.bump();
+    Some((buf, 0))
+}
+
+/// Reads the body of a code, expression or declaration tag up to its
+/// closing `%>`. String, raw string and char literals and comments are
+/// copied through untouched so a `%>` inside them doesn't end the tag.
+/// Also returns the net number of `{` opened outside of literals.
+/// Returns `None` if the input ends first.
+fn scan_rust(cursor: &mut Cursor) -> Option<(String, i64)> {
+    let mut buf = String::new();
+    let mut depth = 0;
+    let mut prev = ' ';
+
+    loop {
+        let rest = cursor.rest();
+        if rest.starts_with("%>") {
+            cursor.bump();
+            cursor.bump();
+            return Some((buf, depth));
+        }
+
+        if rest.starts_with("//") {
+            while cursor.peek()? != '\n' {
+                buf.push(cursor.bump()?);
+            }
+            continue;
+        }
+
+        if rest.starts_with("/*") {
+            let mut nesting = 0;
+            loop {
+                let rest = cursor.rest();
+                if rest.starts_with("/*") {
+                    nesting += 1;
+                } else if rest.starts_with("*/") {
+                    nesting -= 1;
+                    if nesting == 0 {
+                        buf.push(cursor.bump()?);
+                        buf.push(cursor.bump()?);
+                        break;
+                    }
+                }
+                buf.push(cursor.bump()?);
+            }
+            prev = '/';
+            continue;
+        }
+
+        let identifier = prev.is_alphanumeric() || prev == '_';
+        if !identifier {
+            if let Some(hashes) = raw_string_start(rest) {
+                // `r`/`br`, the hashes and the opening quote.
+                let prefix = rest.find('"').unwrap() + 1;
+                for _ in 0..prefix {
+                    buf.push(cursor.bump()?);
+                }
+                let closing = format!("\"{}", "#".repeat(hashes));
+                while !cursor.rest().starts_with(&closing) {
+                    buf.push(cursor.bump()?);
+                }
+                for _ in 0..closing.len() {
+                    buf.push(cursor.bump()?);
+                }
+                prev = '"';
+                continue;
+            }
+            if rest.starts_with("b\"") || rest.starts_with("b'") {
+                buf.push(cursor.bump()?);
+            }
+        }
+
+        let c = cursor.bump()?;
+        buf.push(c);
+        match c {
+            '"' => loop {
+                let c = cursor.bump()?;
+                buf.push(c);
+                match c {
+                    '\\' => buf.push(cursor.bump()?),
+                    '"' => break,
+                    _ => {}
+                }
+            },
+            '\'' => {
+                // A char literal, or a lifetime/label like `'a` or `'outer:`.
+                let rest = cursor.rest();
+                let mut chars = rest.chars();
+                let is_char = match chars.next() {
+                    Some('\\') => true,
+                    Some(_) => chars.next() == Some('\''),
+                    None => false,
+                };
+                if is_char {
+                    loop {
+                        let c = cursor.bump()?;
+                        buf.push(c);
+                        match c {
+                            '\\' => buf.push(cursor.bump()?),
+                            '\'' => break,
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+        prev = c;
+    }
+}
+
+/// If `rest` starts a raw string (`r"`, `r#"`, `br##"`, ...), returns the
+/// number of `#`s.
+fn raw_string_start(rest: &str) -> Option<usize> {
+    let rest = rest.strip_prefix('b').unwrap_or(rest);
+    let rest = rest.strip_prefix('r')?;
+    let hashes = rest.len() - rest.trim_start_matches('#').len();
+    rest[hashes..].starts_with('"').then_some(hashes)
+}
+
+/// Where in the template a parse error was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseLocation {
+    /// Template name, filled in by whoever knows which file was parsed.
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    /// Number of characters to underline, starting at `column`.
+    pub width: usize,
+    /// The template line at `line`.
+    pub snippet: String,
+    /// Numbered template lines around `line`.
+    pub excerpt: Vec<(usize, String)>,
+}
+
+impl ParseLocation {
+    fn new(input: &str, line: usize, column: usize, width: usize) -> Box<Self> {
+        Box::new(ParseLocation {
+            file: None,
+            line,
+            column,
+            width: width.max(1),
+            snippet: input.lines().nth(line - 1).unwrap_or("").to_string(),
+            excerpt: numbered_lines(input, line, 2),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum ParseError {
-    UnclosedTag,
+    UnclosedTag(Box<ParseLocation>),
+    EmptyExpression(Box<ParseLocation>),
+    UnknownDirective {
+        name: String,
+        location: Box<ParseLocation>,
+    },
+    MisplacedDeclaration(Box<ParseLocation>),
+}
+
+impl ParseError {
+    pub fn location(&self) -> &ParseLocation {
+        match self {
+            ParseError::UnclosedTag(location)
+            | ParseError::EmptyExpression(location)
+            | ParseError::UnknownDirective { location, .. }
+            | ParseError::MisplacedDeclaration(location) => location,
+        }
+    }
+
+    /// Records which template the error was found in.
+    pub fn with_file(mut self, file: &str) -> Self {
+        let location = match &mut self {
+            ParseError::UnclosedTag(location)
+            | ParseError::EmptyExpression(location)
+            | ParseError::UnknownDirective { location, .. }
+            | ParseError::MisplacedDeclaration(location) => location,
+        };
+        location.file = Some(file.to_string());
+        self
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ParseError::UnclosedTag(_) => "unclosed `<%` tag".to_string(),
+            ParseError::EmptyExpression(_) => "empty `<%= %>` expression".to_string(),
+            ParseError::UnknownDirective { name, .. } if name.is_empty() => {
+                "empty `<%@ %>` directive".to_string()
+            }
+            ParseError::UnknownDirective { name, .. } => format!("unknown directive `{}`", name),
+            ParseError::MisplacedDeclaration(_) => {
+                "`<%! %>` declaration inside a code block".to_string()
+            }
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            ParseError::UnclosedTag(_) => "opened here, but never closed with `%>`",
+            ParseError::EmptyExpression(_) => "nothing to output",
+            ParseError::UnknownDirective { .. } => "not a known directive",
+            ParseError::MisplacedDeclaration(_) => "declared inside an unclosed `{`",
+        }
+    }
+
+    pub fn help(&self) -> Option<String> {
+        match self {
+            ParseError::UnknownDirective { .. } => {
+                Some(format!("known directives: {}", DIRECTIVES.join(", ")))
+            }
+            ParseError::UnclosedTag(_) => {
+                Some("a `%>` inside a string literal or comment doesn't close the tag".to_string())
+            }
+            ParseError::MisplacedDeclaration(_) => Some(
+                "declarations are hoisted to module level; move it outside of the block"
+                    .to_string(),
+            ),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            ParseError::UnclosedTag => write!(f, "Unclosed <% %> tag"),
+        let location = self.location();
+        let gutter = " ".repeat(location.line.to_string().len());
+
+        writeln!(f, "error: {}", self.message())?;
+        match &location.file {
+            Some(file) => writeln!(
+                f,
+                "{}--> {}:{}:{}",
+                gutter, file, location.line, location.column
+            )?,
+            None => writeln!(f, "{}--> {}:{}", gutter, location.line, location.column)?,
         }
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", location.line, location.snippet)?;
+        write!(
+            f,
+            "{} | {}{} {}",
+            gutter,
+            " ".repeat(location.column - 1),
+            "^".repeat(location.width),
+            self.label()
+        )?;
+        if let Some(help) = self.help() {
+            write!(f, "\n{} = help: {}", gutter, help)?;
+        }
+        Ok(())
     }
 }
 
@@ -155,21 +651,30 @@ mod tests {
     fn test_parse_text() {
         let parser = Parser::new();
         let result = parser.parse("Hello World").unwrap();
-        assert_eq!(result.tokens, vec![Token::Text("Hello World".to_string())]);
+        assert_eq!(
+            result.tokens,
+            vec![Token::Text(
+                "Hello World".to_string(),
+                Span::new(0, 11, 1, 1)
+            )]
+        );
     }
 
     #[test]
     fn test_parse_expression() {
         let parser = Parser::new();
         let result = parser.parse("<%= name %>").unwrap();
-        assert_eq!(result.tokens, vec![Token::Expression("name".to_string())]);
+        assert_eq!(
+            result.tokens,
+            vec![Token::Expression("name".to_string(), Span::new(4, 8, 1, 5))]
+        );
     }
 
     #[test]
     fn test_parse_directive() {
         let parser = Parser::new();
-        let result = parser.parse("<%@ database mysql=\"test\" %>").unwrap();
-        assert_eq!(result.directives, vec!["database mysql=\"test\""]);
+        let result = parser.parse("<%@ dep rusqlite = \"0.32\" %>").unwrap();
+        assert_eq!(result.directives, vec!["dep rusqlite = \"0.32\""]);
     }
 
     #[test]
@@ -178,4 +683,195 @@ mod tests {
         let result = parser.parse("<%! static mut COUNT: i32 = 0; %>").unwrap();
         assert_eq!(result.declarations, vec!["static mut COUNT: i32 = 0;"]);
     }
+
+    #[test]
+    fn test_parse_spans() {
+        let parser = Parser::new();
+        let result = parser
+            .parse("<p>\n  <%\n    let x = 1;\n  %>é<%= x %>")
+            .unwrap();
+        assert_eq!(result.tokens[0].span(), Span::new(0, 6, 1, 1));
+        assert_eq!(result.tokens[1].span(), Span::new(13, 23, 3, 5));
+        assert_eq!(result.tokens[2].span(), Span::new(28, 30, 4, 5));
+        assert_eq!(result.tokens[3].span(), Span::new(34, 35, 4, 10));
+    }
+
+    #[test]
+    fn test_error_unclosed_tag() {
+        let parser = Parser::new();
+        let err = parser.parse("<p>\n  <% let x = 1;\n</p>").unwrap_err();
+        assert!(matches!(err, ParseError::UnclosedTag(_)));
+        let location = err.location();
+        assert_eq!((location.line, location.column, location.width), (2, 3, 2));
+        assert_eq!(location.snippet, "  <% let x = 1;");
+    }
+
+    #[test]
+    fn test_error_empty_expression() {
+        let parser = Parser::new();
+        let err = parser.parse("<p><%=   %></p>").unwrap_err();
+        assert!(matches!(err, ParseError::EmptyExpression(_)));
+        let location = err.location();
+        assert_eq!((location.line, location.column, location.width), (1, 4, 8));
+    }
+
+    #[test]
+    fn test_error_unknown_directive() {
+        let parser = Parser::new();
+        let err = parser
+            .parse("<%@ use std::fmt %>\n<%@ database mysql=\"test\" %>")
+            .unwrap_err();
+        let ParseError::UnknownDirective { name, location } = &err else {
+            panic!("expected unknown directive, got {:?}", err);
+        };
+        assert_eq!(name, "database");
+        assert_eq!((location.line, location.column, location.width), (2, 5, 8));
+    }
+
+    #[test]
+    fn test_error_misplaced_declaration() {
+        let parser = Parser::new();
+        let err = parser
+            .parse("<% for i in 0..3 { %>\n  <%! static N: i32 = 0; %>\n<% } %>")
+            .unwrap_err();
+        assert!(matches!(err, ParseError::MisplacedDeclaration(_)));
+        assert_eq!(err.location().line, 2);
+
+        assert!(parser
+            .parse("<% if x { %>a<% } else { %>b<% } %><%! static N: i32 = 0; %>")
+            .is_ok());
+    }
+
+    #[test]
+    fn test_error_display() {
+        let parser = Parser::new();
+        let err = parser
+            .parse("<p>\n<%= %>")
+            .unwrap_err()
+            .with_file("page.rsp");
+        assert_eq!(
+            err.to_string(),
+            "error: empty `<%= %>` expression\n --> page.rsp:2:1\n  |\n2 | <%= %>\n  | ^^^^^^ nothing to output"
+        );
+    }
+
+    #[test]
+    fn test_close_marker_inside_literals() {
+        let parser = Parser::new();
+        let cases = [
+            r#"format!("100%>")"#,
+            r##"r#"a "%>" b"#"##,
+            r#"b"%>".len()"#,
+            r#"'%' as u8 + b'>' - '\'' as u8"#,
+            "x /* %> /* nested %> */ %> */ + 1",
+            "1 // 100%> done\n + 2",
+            r#""escaped \" %>""#,
+        ];
+        for expr in cases {
+            let input = format!("<p><%= {} %></p>", expr);
+            let result = parser.parse(&input).unwrap();
+            assert_eq!(result.tokens.len(), 3, "{}", expr);
+            assert_eq!(
+                result.tokens[1],
+                Token::Expression(expr.to_string(), Span::new(7, 7 + expr.len(), 1, 8)),
+            );
+        }
+    }
+
+    #[test]
+    fn test_lifetimes_are_not_char_literals() {
+        let parser = Parser::new();
+        let result = parser
+            .parse(
+                "<%! fn f<'a>(s: &'a str) -> &'a str { s } %>x<% 'outer: loop { break 'outer; } %>",
+            )
+            .unwrap();
+        assert_eq!(result.tokens.len(), 3);
+        assert_eq!(
+            result.tokens[1],
+            Token::Text("x".to_string(), Span::new(44, 45, 1, 45))
+        );
+    }
+
+    #[test]
+    fn test_braces_in_strings_do_not_nest() {
+        let parser = Parser::new();
+        assert!(parser
+            .parse("<% let s = \"{\"; %><%! static N: i32 = 0; %>")
+            .is_ok());
+    }
+
+    #[test]
+    fn test_comments() {
+        let parser = Parser::new();
+        let result = parser
+            .parse("a<%-- note <%= x %> 100%> --%>b<%--\n--%><%= y %>")
+            .unwrap();
+        assert_eq!(result.tokens.len(), 3);
+        assert_eq!(
+            result.tokens[0],
+            Token::Text("a".to_string(), Span::new(0, 1, 1, 1))
+        );
+        assert_eq!(
+            result.tokens[1],
+            Token::Text("b".to_string(), Span::new(30, 31, 1, 31))
+        );
+        assert_eq!(
+            result.tokens[2],
+            Token::Expression("y".to_string(), Span::new(44, 45, 2, 9))
+        );
+
+        let err = parser.parse("a\n <%-- never closed %>").unwrap_err();
+        assert!(matches!(err, ParseError::UnclosedTag(_)));
+        assert_eq!((err.location().line, err.location().column), (2, 2));
+    }
+
+    #[test]
+    fn test_trim_markers() {
+        let parser = Parser::new();
+        let result = parser
+            .parse("<ul>\n  <%- for i in 0..2 { -%>\n  <li><%= i -%> </li>\n  <%- } -%>  \n</ul>")
+            .unwrap();
+        let text: Vec<&str> = result
+            .tokens
+            .iter()
+            .filter_map(|t| match t {
+                Token::Text(text, _) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(text, ["<ul>\n", "  <li>", " </li>\n", "</ul>"]);
+        assert_eq!(result.tokens[0].span(), Span::new(0, 5, 1, 1));
+        assert_eq!(
+            result.tokens[1],
+            Token::Code("for i in 0..2 {".to_string(), Span::new(11, 26, 2, 7))
+        );
+        assert_eq!(
+            result.tokens[3],
+            Token::Expression("i".to_string(), Span::new(41, 42, 3, 11))
+        );
+
+        let result = parser.parse("<%@ use std::fmt -%>\r\nx").unwrap();
+        assert_eq!(result.directives, ["use std::fmt"]);
+        assert_eq!(
+            result.tokens[1],
+            Token::Text("x".to_string(), Span::new(22, 23, 2, 1))
+        );
+    }
+
+    #[test]
+    fn test_raw_expression() {
+        let parser = Parser::new();
+        let result = parser.parse("<%== html %><%-== a == b -%>").unwrap();
+        assert_eq!(
+            result.tokens,
+            vec![
+                Token::RawExpression("html".to_string(), Span::new(5, 9, 1, 6)),
+                Token::RawExpression("a == b".to_string(), Span::new(18, 24, 1, 19)),
+            ]
+        );
+
+        let err = parser.parse("<%== %>").unwrap_err();
+        assert!(matches!(err, ParseError::EmptyExpression(_)));
+    }
 }
@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Routes from a `routes.toml` file, mapping URL patterns to pages:
+///
+/// ```toml
+/// "/post/{id}" = "post.rsp"
+/// "/user/{name}/posts" = "user/posts.rsp"
+/// ```
+///
+/// A `{name}` segment matches any single path segment; the captured values
+/// reach the page as `req.route`. Pages are relative to the docroot.
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+    routes: Vec<Route>,
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    segments: Vec<Segment>,
+    page: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+/// The page a path routes to, with the parameters captured on the way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteMatch {
+    pub page: PathBuf,
+    pub params: HashMap<String, String>,
+}
+
+#[derive(Error, Debug)]
+pub enum RouteError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid routes file: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("Invalid route {0:?}: {1}")]
+    Pattern(String, String),
+}
+
+impl Routes {
+    pub fn load(path: &Path) -> Result<Self, RouteError> {
+        Routes::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, RouteError> {
+        let table: toml::Table = text.parse()?;
+        let mut routes = Vec::new();
+        for (pattern, page) in table {
+            let Some(page) = page.as_str() else {
+                return Err(RouteError::Pattern(
+                    pattern,
+                    "the page must be a string".to_string(),
+                ));
+            };
+            let segments = parse_pattern(&pattern)?;
+            routes.push(Route {
+                segments,
+                page: PathBuf::from(page.trim_start_matches('/')),
+            });
+        }
+        Ok(Routes { routes })
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// The route matching `path`. When several do, literal segments win
+    /// over parameters, left to right, so `/post/new` beats `/post/{id}`.
+    pub fn resolve(&self, path: &str) -> Option<RouteMatch> {
+        let parts: Vec<&str> = split(path).collect();
+        self.routes
+            .iter()
+            .filter_map(|route| Some((route, route.captures(&parts)?)))
+            .max_by_key(|(route, _)| {
+                route
+                    .segments
+                    .iter()
+                    .map(|s| matches!(s, Segment::Literal(_)))
+                    .collect::<Vec<_>>()
+            })
+            .map(|(route, params)| RouteMatch {
+                page: route.page.clone(),
+                params,
+            })
+    }
+}
+
+impl Route {
+    fn captures(&self, parts: &[&str]) -> Option<HashMap<String, String>> {
+        if parts.len() != self.segments.len() {
+            return None;
+        }
+        let mut params = HashMap::new();
+        for (segment, part) in self.segments.iter().zip(parts) {
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => {
+                    params.insert(name.clone(), percent_decode(part));
+                }
+            }
+        }
+        Some(params)
+    }
+}
+
+fn split(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+fn parse_pattern(pattern: &str) -> Result<Vec<Segment>, RouteError> {
+    let invalid = |reason: &str| RouteError::Pattern(pattern.to_string(), reason.to_string());
+    if !pattern.starts_with('/') {
+        return Err(invalid("patterns start with `/`"));
+    }
+    let mut names = Vec::new();
+    split(pattern)
+        .map(|part| match part.strip_prefix('{') {
+            Some(rest) => {
+                let name = rest
+                    .strip_suffix('}')
+                    .filter(|n| !n.is_empty() && !n.contains(['{', '}']))
+                    .ok_or_else(|| invalid("parameters look like `{name}`"))?;
+                if names.contains(&name) {
+                    return Err(invalid("a parameter name is used twice"));
+                }
+                names.push(name);
+                Ok(Segment::Param(name.to_string()))
+            }
+            None if part.contains(['{', '}']) => {
+                Err(invalid("a parameter must be a whole segment"))
+            }
+            None => Ok(Segment::Literal(part.to_string())),
+        })
+        .collect()
+}
+
+/// Decodes `%XX` escapes in a URL path. Unlike a query string, `+` stays
+/// as it is.
+pub(crate) fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|h| std::str::from_utf8(h).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(byte) if b == b'%' => {
+                bytes.push(byte);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// What a request path names in the docroot, see `find_page`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PageLookup {
+    /// A page to render, relative to the docroot.
+    Page(String),
+    /// The page lives at another URL, percent-encoded for a `Location`
+    /// header; send the client there.
+    Redirect(String),
+    /// No page; maybe a route or a static file.
+    NoPage,
+}
+
+/// Finds the page for the decoded `uri_path`, with `index` as the page of
+/// a directory. Besides `/post.rsp`, `/post` finds `post.rsp` and `/forum/`
+/// the index of `forum/`, while `/forum` redirects to `/forum/`. With
+/// `canonical_urls`, `/post.rsp` and `/forum/index.rsp` redirect to `/post`
+/// and `/forum/`. Only GET and HEAD requests are redirected, so form posts
+/// keep working.
+pub fn find_page(
+    docroot: &Path,
+    index: &str,
+    canonical_urls: bool,
+    uri_path: &str,
+    method: &str,
+) -> PageLookup {
+    let can_redirect = method == "GET" || method == "HEAD";
+    let path = uri_path.trim_start_matches('/');
+    let page_at = |page: String| {
+        if docroot.join(&page).is_file() {
+            PageLookup::Page(page)
+        } else {
+            PageLookup::NoPage
+        }
+    };
+
+    if path.is_empty() || path.ends_with('/') {
+        return page_at(format!("{}{}", path, index));
+    }
+
+    if let Some(stem) = path.strip_suffix(".rsp") {
+        if !docroot.join(path).is_file() {
+            return PageLookup::NoPage;
+        }
+        if can_redirect && canonical_urls {
+            let location = match path.strip_suffix(index) {
+                Some(dir) if dir.is_empty() || dir.ends_with('/') => format!("/{}", dir),
+                _ => format!("/{}", stem),
+            };
+            return PageLookup::Redirect(encode_path(&location));
+        }
+        return PageLookup::Page(path.to_string());
+    }
+
+    // Static files keep their URLs.
+    let file = docroot.join(path);
+    if file.is_file() {
+        return PageLookup::NoPage;
+    }
+    if let PageLookup::Page(page) = page_at(format!("{}.rsp", path)) {
+        return PageLookup::Page(page);
+    }
+    if file.is_dir() {
+        if can_redirect {
+            return PageLookup::Redirect(encode_path(&format!("/{}/", path)));
+        }
+        return page_at(format!("{}/{}", path, index));
+    }
+    PageLookup::NoPage
+}
+
+/// Percent-encodes a decoded path for use in a `Location` header.
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_routes() {
+        let routes = Routes::parse(
+            r#"
+"/post/{id}" = "post.rsp"
+"/post/new" = "/post_form.rsp"
+"/user/{name}/posts/{page}" = "user/posts.rsp"
+"/" = "home.rsp"
+"#,
+        )
+        .unwrap();
+
+        let post = routes.resolve("/post/42").unwrap();
+        assert_eq!(post.page, PathBuf::from("post.rsp"));
+        assert_eq!(post.params["id"], "42");
+
+        let new = routes.resolve("/post/new/").unwrap();
+        assert_eq!(new.page, PathBuf::from("post_form.rsp"));
+        assert!(new.params.is_empty());
+
+        let posts = routes.resolve("/user/a%20b+c/posts/2").unwrap();
+        assert_eq!(posts.page, PathBuf::from("user/posts.rsp"));
+        assert_eq!(posts.params["name"], "a b+c");
+        assert_eq!(posts.params["page"], "2");
+
+        assert_eq!(routes.resolve("/").unwrap().page, PathBuf::from("home.rsp"));
+        assert!(routes.resolve("/post").is_none());
+        assert!(routes.resolve("/post/1/edit").is_none());
+    }
+
+    fn docroot(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rsp-urls-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("forum")).unwrap();
+        for file in [
+            "index.rsp",
+            "post.rsp",
+            "forum/index.rsp",
+            "my page.rsp",
+            "style.css",
+        ] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        dir
+    }
+
+    fn page(path: &str) -> PageLookup {
+        PageLookup::Page(path.to_string())
+    }
+
+    fn redirect(location: &str) -> PageLookup {
+        PageLookup::Redirect(location.to_string())
+    }
+
+    #[test]
+    fn test_find_page() {
+        let dir = docroot("plain");
+        let find = |path: &str, method: &str| find_page(&dir, "index.rsp", false, path, method);
+
+        assert_eq!(find("/", "GET"), page("index.rsp"));
+        assert_eq!(find("/post", "GET"), page("post.rsp"));
+        assert_eq!(find("/post.rsp", "GET"), page("post.rsp"));
+        assert_eq!(find("/forum/", "GET"), page("forum/index.rsp"));
+        assert_eq!(find("/forum/index.rsp", "GET"), page("forum/index.rsp"));
+        assert_eq!(find("/my page", "GET"), page("my page.rsp"));
+
+        // A directory is only found with its trailing slash; form posts to
+        // it still reach its index.
+        assert_eq!(find("/forum", "GET"), redirect("/forum/"));
+        assert_eq!(find("/forum", "HEAD"), redirect("/forum/"));
+        assert_eq!(find("/forum", "POST"), page("forum/index.rsp"));
+
+        // Static files, routes and 404s are handled elsewhere.
+        assert_eq!(find("/style.css", "GET"), PageLookup::NoPage);
+        assert_eq!(find("/missing", "GET"), PageLookup::NoPage);
+        assert_eq!(find("/missing.rsp", "GET"), PageLookup::NoPage);
+        assert_eq!(find("/missing/", "GET"), PageLookup::NoPage);
+    }
+
+    #[test]
+    fn test_find_page_canonical_urls() {
+        let dir = docroot("canonical");
+        let find = |path: &str, method: &str| find_page(&dir, "index.rsp", true, path, method);
+
+        assert_eq!(find("/post.rsp", "GET"), redirect("/post"));
+        assert_eq!(find("/post.rsp", "HEAD"), redirect("/post"));
+        assert_eq!(find("/post.rsp", "POST"), page("post.rsp"));
+        assert_eq!(find("/index.rsp", "GET"), redirect("/"));
+        assert_eq!(find("/forum/index.rsp", "GET"), redirect("/forum/"));
+        assert_eq!(find("/my page.rsp", "GET"), redirect("/my%20page"));
+        assert_eq!(find("/missing.rsp", "GET"), PageLookup::NoPage);
+
+        assert_eq!(find("/post", "GET"), page("post.rsp"));
+        assert_eq!(find("/post", "POST"), page("post.rsp"));
+        assert_eq!(find("/forum/", "GET"), page("forum/index.rsp"));
+        assert_eq!(find("/forum", "GET"), redirect("/forum/"));
+    }
+
+    #[test]
+    fn test_encode_path() {
+        assert_eq!(encode_path("/a-b_c.d~/e"), "/a-b_c.d~/e");
+        assert_eq!(encode_path("/my page?#%"), "/my%20page%3F%23%25");
+        assert_eq!(encode_path("/café"), "/caf%C3%A9");
+    }
+
+    #[test]
+    fn test_invalid_routes() {
+        for text in [
+            r#""post/{id}" = "post.rsp""#,
+            r#""/post/{id" = "post.rsp""#,
+            r#""/post/id-{id}" = "post.rsp""#,
+            r#""/{a}/{a}" = "post.rsp""#,
+            r#""/post" = 3"#,
+        ] {
+            assert!(
+                matches!(Routes::parse(text), Err(RouteError::Pattern(..))),
+                "{}",
+                text
+            );
+        }
+        assert!(matches!(
+            Routes::parse("not toml"),
+            Err(RouteError::Toml(_))
+        ));
+    }
+}
@@ -0,0 +1,186 @@
+use crate::parser::Span;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A template file that contributed code to a generated page.
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    /// Name used in diagnostics, relative to the docroot when possible.
+    pub name: String,
+    pub path: Option<PathBuf>,
+    pub content: String,
+    /// `path` as it was just before `content` was read from it; `None` for
+    /// the page itself, which the caller reads.
+    pub stamp: Option<FileStamp>,
+}
+
+/// Enough of a file's metadata to notice that it changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub modified: SystemTime,
+    pub len: u64,
+}
+
+impl FileStamp {
+    pub fn of(path: &Path) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(FileStamp {
+            modified: metadata.modified()?,
+            len: metadata.len(),
+        })
+    }
+}
+
+/// A position in one of the template files of a page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateLocation {
+    /// Index into `SourceMap::files`.
+    pub source: usize,
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Maps lines of the generated Rust source back to the template lines they
+/// were generated from.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    /// Index 0 is the page itself, followed by every included or extended file.
+    pub files: Vec<SourceFile>,
+    lines: Vec<Option<MappedLine>>,
+}
+
+/// Generated column `gen_column` of a line corresponds to `column` on
+/// `line` of file `source`; other columns are offset from there.
+#[derive(Debug, Clone, Copy)]
+struct MappedLine {
+    source: usize,
+    line: usize,
+    gen_column: usize,
+    column: usize,
+}
+
+impl SourceMap {
+    /// Looks up a 1-based line and column of the generated source.
+    pub fn lookup(&self, line: usize, column: usize) -> Option<TemplateLocation> {
+        let mapped = (*self.lines.get(line.checked_sub(1)?)?)?;
+        let file = self.files.get(mapped.source)?;
+        Some(TemplateLocation {
+            source: mapped.source,
+            file: file.name.clone(),
+            line: mapped.line,
+            column: (mapped.column + column)
+                .saturating_sub(mapped.gen_column)
+                .max(1),
+        })
+    }
+
+    /// Numbered lines of file `source` around `line`.
+    pub fn lines_around(&self, source: usize, line: usize, context: usize) -> Vec<(usize, String)> {
+        self.files
+            .get(source)
+            .map(|file| numbered_lines(&file.content, line, context))
+            .unwrap_or_default()
+    }
+
+    /// Returns the template line a location points at.
+    pub fn line_text(&self, location: &TemplateLocation) -> Option<&str> {
+        let file = self.files.get(location.source)?;
+        file.content.lines().nth(location.line.checked_sub(1)?)
+    }
+}
+
+/// Lines `line - context ..= line + context` of `text`, numbered from 1.
+pub(crate) fn numbered_lines(text: &str, line: usize, context: usize) -> Vec<(usize, String)> {
+    let first = line.saturating_sub(context).max(1);
+    text.lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.to_string()))
+        .skip(first - 1)
+        .take(line + context + 1 - first)
+        .collect()
+}
+
+/// Generated code for one part of a page, with the template origin of
+/// every line it contains.
+#[derive(Debug, Default)]
+pub(crate) struct Section {
+    code: String,
+    lines: Vec<Option<MappedLine>>,
+}
+
+impl Section {
+    pub(crate) fn contains(&self, text: &str) -> bool {
+        self.code.contains(text)
+    }
+
+    /// Appends `content` wrapped in `first_prefix` and `suffix`, mapping
+    /// each line back to `span`. Continuation lines get `rest_prefix`.
+    pub(crate) fn push_mapped(
+        &mut self,
+        first_prefix: &str,
+        rest_prefix: &str,
+        content: &str,
+        suffix: &str,
+        span: Span,
+    ) {
+        let lines: Vec<&str> = if content.is_empty() {
+            vec![""]
+        } else {
+            content.lines().collect()
+        };
+        let last = lines.len() - 1;
+
+        for (i, line) in lines.into_iter().enumerate() {
+            let prefix = if i == 0 { first_prefix } else { rest_prefix };
+            self.code.push_str(prefix);
+            self.code.push_str(line);
+            if i == last {
+                self.code.push_str(suffix);
+            }
+            self.code.push('\n');
+            self.lines.push(Some(MappedLine {
+                source: span.source,
+                line: span.line + i,
+                gen_column: prefix.chars().count() + 1,
+                column: if i == 0 { span.column } else { 1 },
+            }));
+        }
+    }
+
+    /// Appends generated code that has no template counterpart.
+    pub(crate) fn push_unmapped(&mut self, code: &str) {
+        for line in code.lines() {
+            self.push_line(line);
+        }
+    }
+
+    pub(crate) fn push_line(&mut self, line: &str) {
+        self.code.push_str(line);
+        self.code.push('\n');
+        self.lines.push(None);
+    }
+
+    /// Inserts unmapped lines before everything pushed so far.
+    pub(crate) fn prepend_unmapped(&mut self, code: &str) {
+        let mut section = Section::default();
+        section.push_unmapped(code);
+        section.append(std::mem::take(self));
+        *self = section;
+    }
+
+    pub(crate) fn append(&mut self, other: Section) {
+        self.code.push_str(&other.code);
+        self.lines.extend(other.lines);
+    }
+
+    pub(crate) fn finish(self, files: Vec<SourceFile>) -> (String, SourceMap) {
+        (
+            self.code,
+            SourceMap {
+                files,
+                lines: self.lines,
+            },
+        )
+    }
+}
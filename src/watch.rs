@@ -0,0 +1,323 @@
+use crate::engine::RspEngine;
+use notify::{EventKind, RecursiveMode, Watcher as _};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+use walkdir::WalkDir;
+
+/// How long the docroot has to stay quiet before a batch of changes is
+/// rebuilt. Editors often write a file in several steps.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Watches the docroot and compiles pages in the background as they change,
+/// so the next request finds them ready.
+///
+/// Every page is compiled once at startup. After that, editing a page
+/// rebuilds it and every page that includes or extends it; `on_rebuild` is
+/// called once a batch of changes has been rebuilt without errors. Changes
+/// to other files (stylesheets, scripts, images) call `on_rebuild` right
+/// away.
+pub struct Watcher {
+    // Dropping this stops the events, which ends the background thread.
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl Watcher {
+    pub fn start(
+        engine: Arc<RspEngine>,
+        docroot: &Path,
+        on_rebuild: impl Fn() + Send + 'static,
+    ) -> notify::Result<Self> {
+        let (tx, rx) = channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        watcher.watch(docroot, RecursiveMode::Recursive)?;
+
+        let docroot = docroot.to_path_buf();
+        let cache_dir = engine
+            .cache_dir()
+            .canonicalize()
+            .unwrap_or_else(|_| engine.cache_dir().to_path_buf());
+        std::thread::spawn(move || {
+            let mut builder = Builder {
+                engine,
+                docroot,
+                cache_dir,
+                dependencies: Dependencies::default(),
+            };
+            builder.build_all();
+            while let Some(changed) = builder.next_batch(&rx) {
+                if builder.rebuild(changed) {
+                    on_rebuild();
+                }
+            }
+        });
+
+        Ok(Watcher { _watcher: watcher })
+    }
+}
+
+struct Builder {
+    engine: Arc<RspEngine>,
+    docroot: PathBuf,
+    cache_dir: PathBuf,
+    dependencies: Dependencies,
+}
+
+impl Builder {
+    /// Compiles every page in the docroot. Files that fail on their own but
+    /// are included by a page that compiles are partials, not broken pages,
+    /// so their errors aren't reported.
+    fn build_all(&mut self) {
+        let pages: Vec<PathBuf> = WalkDir::new(&self.docroot)
+            .follow_links(true)
+            .into_iter()
+            .filter_entry(|e| !self.ignored(e.path()))
+            .filter_map(|e| e.ok())
+            .map(|e| canonical(e.path()))
+            .filter(|path| is_page(path))
+            .collect();
+
+        let mut failed = Vec::new();
+        for page in pages {
+            match self.engine.compile_file(&page) {
+                Ok(includes) => self.dependencies.update(&page, includes),
+                Err(e) => failed.push((page, e)),
+            }
+        }
+        for (page, e) in failed {
+            if !self.dependencies.is_included(&page) {
+                eprintln!("Error compiling {}: {}", self.name(&page), e);
+            }
+        }
+    }
+
+    /// Waits for changes and collects them until the docroot is quiet.
+    /// `None` once the watcher is gone.
+    fn next_batch(
+        &self,
+        rx: &Receiver<notify::Result<notify::Event>>,
+    ) -> Option<BTreeSet<PathBuf>> {
+        let mut changed = BTreeSet::new();
+        let mut event = rx.recv().ok()?;
+        loop {
+            match event {
+                Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                    changed.extend(
+                        event
+                            .paths
+                            .iter()
+                            .filter(|path| !self.ignored(path))
+                            .map(|path| canonical(path)),
+                    );
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Watch error: {}", e),
+            }
+            event = match rx.recv_timeout(DEBOUNCE) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) if changed.is_empty() => rx.recv().ok()?,
+                Err(RecvTimeoutError::Timeout) => return Some(changed),
+                Err(RecvTimeoutError::Disconnected) => return None,
+            };
+        }
+    }
+
+    /// Rebuilds the changed pages and the pages depending on them. Returns
+    /// whether everything built.
+    fn rebuild(&mut self, changed: BTreeSet<PathBuf>) -> bool {
+        let mut pages = BTreeSet::new();
+        // Included files that were also compiled as pages of their own.
+        // Like in `build_all`, their own errors aren't reported.
+        let mut partials = BTreeSet::new();
+        for path in &changed {
+            if !path.exists() {
+                self.dependencies.remove(path);
+            } else if is_page(path) && !self.dependencies.is_included(path) {
+                pages.insert(path.clone());
+            } else if is_page(path) && self.engine.has_page(path) {
+                partials.insert(path.clone());
+            }
+            pages.extend(self.dependencies.dependents(path));
+        }
+
+        let mut ok = true;
+        for page in pages {
+            match self.engine.compile_file(&page) {
+                Ok(includes) => {
+                    self.dependencies.update(&page, includes);
+                    println!("Rebuilt {}", self.name(&page));
+                }
+                Err(e) => {
+                    eprintln!("Error compiling {}: {}", self.name(&page), e);
+                    ok = false;
+                }
+            }
+        }
+        for partial in partials {
+            if let Ok(includes) = self.engine.compile_file(&partial) {
+                self.dependencies.update(&partial, includes);
+            }
+        }
+        ok
+    }
+
+    /// The compiled-page cache and hidden files (`.git`, editor swap files)
+    /// don't affect any page.
+    fn ignored(&self, path: &Path) -> bool {
+        if path.starts_with(&self.cache_dir) {
+            return true;
+        }
+        let relative = path.strip_prefix(&self.docroot).unwrap_or(path);
+        relative.components().any(|c| {
+            let name = c.as_os_str().to_string_lossy();
+            name.starts_with('.') || name.ends_with('~')
+        })
+    }
+
+    fn name(&self, path: &Path) -> String {
+        path.strip_prefix(&self.docroot)
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    }
+}
+
+/// Which pages include or extend which files, directly or through another
+/// include. Paths are canonical, like `GeneratedCode::includes`.
+#[derive(Debug, Default)]
+struct Dependencies {
+    includes: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl Dependencies {
+    fn update(&mut self, page: &Path, includes: Vec<PathBuf>) {
+        self.includes.insert(page.to_path_buf(), includes);
+    }
+
+    /// Forgets a deleted page, or every page under a deleted directory.
+    fn remove(&mut self, path: &Path) {
+        self.includes.retain(|page, _| !page.starts_with(path));
+    }
+
+    /// The pages that include `file`, or a file under it if it's a
+    /// directory.
+    fn dependents(&self, file: &Path) -> Vec<PathBuf> {
+        self.includes
+            .iter()
+            .filter(|(_, includes)| includes.iter().any(|i| i.starts_with(file)))
+            .map(|(page, _)| page.clone())
+            .collect()
+    }
+
+    fn is_included(&self, file: &Path) -> bool {
+        self.includes.values().flatten().any(|i| i == file)
+    }
+}
+
+fn is_page(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "rsp") && path.is_file()
+}
+
+/// Deleted files can't be canonicalized; their directory usually can.
+fn canonical(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    match (
+        path.parent().and_then(|p| p.canonicalize().ok()),
+        path.file_name(),
+    ) {
+        (Some(dir), Some(name)) => dir.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let mut deps = Dependencies::default();
+        deps.update(
+            Path::new("/www/index.rsp"),
+            vec!["/www/layout.rsp".into(), "/www/parts/header.rsp".into()],
+        );
+        deps.update(
+            Path::new("/www/forum/post.rsp"),
+            vec!["/www/layout.rsp".into()],
+        );
+        deps.update(Path::new("/www/layout.rsp"), vec![]);
+
+        assert_eq!(
+            sorted(deps.dependents(Path::new("/www/layout.rsp"))),
+            vec![
+                PathBuf::from("/www/forum/post.rsp"),
+                PathBuf::from("/www/index.rsp")
+            ]
+        );
+        assert_eq!(
+            deps.dependents(Path::new("/www/parts")),
+            vec![PathBuf::from("/www/index.rsp")]
+        );
+        assert!(deps.dependents(Path::new("/www/index.rsp")).is_empty());
+        assert!(deps.is_included(Path::new("/www/parts/header.rsp")));
+        assert!(!deps.is_included(Path::new("/www/forum/post.rsp")));
+
+        // A page that stops extending the layout no longer depends on it.
+        deps.update(Path::new("/www/index.rsp"), vec![]);
+        assert_eq!(
+            deps.dependents(Path::new("/www/layout.rsp")),
+            vec![PathBuf::from("/www/forum/post.rsp")]
+        );
+
+        deps.remove(Path::new("/www/forum"));
+        assert!(deps.dependents(Path::new("/www/layout.rsp")).is_empty());
+        assert!(!deps.is_included(Path::new("/www/layout.rsp")));
+    }
+
+    #[test]
+    fn test_rebuilds_included_pages() {
+        let dir = std::env::temp_dir().join(format!("rsp-watch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let docroot = dir.canonicalize().unwrap();
+        let header = docroot.join("header.rsp");
+        let index = docroot.join("index.rsp");
+        std::fs::write(&header, "<h1>v1</h1>").unwrap();
+        std::fs::write(&index, "<%@ include \"header.rsp\" %>index").unwrap();
+
+        let engine = Arc::new(RspEngine::new(docroot.join(".rspcache")).unwrap());
+        engine.set_docroot(docroot.clone());
+        engine.set_check_changes(false);
+        let mut builder = Builder {
+            engine: engine.clone(),
+            docroot: docroot.clone(),
+            cache_dir: docroot.join(".rspcache"),
+            dependencies: Dependencies::default(),
+        };
+        builder.build_all();
+        let render =
+            |page: &Path| String::from_utf8(engine.render_file(page).unwrap().content).unwrap();
+        assert_eq!(render(&header), "<h1>v1</h1>");
+
+        // The header is a page too, so both are rebuilt.
+        std::fs::write(&header, "<h1>v2!</h1>").unwrap();
+        assert!(builder.rebuild(BTreeSet::from([header.clone()])));
+        assert_eq!(render(&header), "<h1>v2!</h1>");
+        assert_eq!(render(&index), "<h1>v2!</h1>index");
+
+        // One that only works inside its page doesn't count as a failure.
+        std::fs::write(&header, "<h1><%= title %></h1>").unwrap();
+        std::fs::write(&index, "<% let title = 3; %><%@ include \"header.rsp\" %>").unwrap();
+        assert!(builder.rebuild(BTreeSet::from([header.clone(), index.clone()])));
+        assert_eq!(render(&index), "<h1>3</h1>");
+    }
+}